@@ -0,0 +1,194 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{Config, Global};
+
+pub const NONCE_LEN: usize = 32;
+
+/// The role of a connected client, each role can do everything the roles before it can
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Access {
+    /// Only receives sensors and values
+    ReadOnly,
+    /// May also set the pwm channels
+    Operator,
+    /// May also add, configure and remove sensors
+    Admin,
+}
+
+impl Access {
+    fn to_byte(self) -> u8 {
+        match self {
+            Access::ReadOnly => 0,
+            Access::Admin => 1,
+            Access::Operator => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Access> {
+        match b {
+            0 => Some(Access::ReadOnly),
+            1 => Some(Access::Admin),
+            2 => Some(Access::Operator),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Access {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "read" => Access::ReadOnly,
+            "operator" => Access::Operator,
+            // full is what admin was called before there were roles
+            "admin" | "full" => Access::Admin,
+            _ => anyhow::bail!("{} is not a role, expected read, operator or admin", s),
+        })
+    }
+}
+
+/// Random challenge sent with the hello, the client proves it knows the secret by signing it
+pub fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Figure out the access level of a client from what it sent in its ready,
+/// `certificate` is the access granted by a verified TLS client certificate
+pub fn authenticate(
+    nonce: &[u8],
+    hmac: &[u8],
+    token: &str,
+    certificate: Option<Access>,
+) -> Result<Access> {
+    let cfg = Config::global();
+    let tokens = tokens()?;
+
+    let client_certs = cfg
+        .tls
+        .as_ref()
+        .map(|tls| tls.client_ca.is_some())
+        .unwrap_or(false);
+
+    if cfg.secret.is_none() && tokens.is_empty() && !client_certs {
+        // Nothing configured, keep the old behaviour where everyone on the network is trusted
+        return Ok(Access::Admin);
+    }
+
+    let access = certificate.unwrap_or(Access::ReadOnly);
+
+    if let Some(ref secret) = cfg.secret {
+        if !hmac.is_empty() {
+            let mut mac = Hmac::<Sha256>::new_varkey(secret)
+                .map_err(|e| anyhow::format_err!("Invalid secret {:?}", e))?;
+            mac.update(nonce);
+
+            if mac.verify(hmac).is_ok() {
+                return Ok(Access::Admin);
+            }
+
+            log::warn!("Client sent an invalid hmac");
+        }
+    }
+
+    if !token.is_empty() {
+        match tokens.get(token_key(token))? {
+            Some(stored) => {
+                let stored = stored
+                    .first()
+                    .and_then(|b| Access::from_byte(*b))
+                    .unwrap_or(Access::ReadOnly);
+
+                return Ok(access.max(stored));
+            }
+            None => log::warn!("Client sent an unknown token"),
+        }
+    }
+
+    Ok(access)
+}
+
+/// Access for a client certificate that passed verification against the client CA,
+/// certificates without a stored fingerprint are read only
+pub fn certificate_access(der: &[u8]) -> Result<Access> {
+    let access = certificates()?
+        .get(Sha256::digest(der))?
+        .and_then(|a| a.first().and_then(|b| Access::from_byte(*b)))
+        .unwrap_or(Access::ReadOnly);
+
+    Ok(access)
+}
+
+/// Store the access for a certificate by its SHA-256 fingerprint in hex, colons are allowed
+pub fn add_certificate(fingerprint: &str, access: Access) -> Result<()> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        anyhow::bail!("{} is not a SHA-256 fingerprint", fingerprint);
+    }
+
+    let fingerprint = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+
+    certificates()?.insert(fingerprint, &[access.to_byte()])?;
+    Ok(())
+}
+
+pub fn add_token(token: &str, access: Access) -> Result<()> {
+    tokens()?.insert(token_key(token), &[access.to_byte()])?;
+    Ok(())
+}
+
+pub fn revoke_token(token: &str) -> Result<bool> {
+    Ok(tokens()?.remove(token_key(token))?.is_some())
+}
+
+fn tokens() -> Result<sled::Tree> {
+    Ok(sled::Db::global().open_tree("auth-tokens")?)
+}
+
+fn certificates() -> Result<sled::Tree> {
+    Ok(sled::Db::global().open_tree("auth-certificates")?)
+}
+
+// Only a hash of the token is stored so a copy of the database does not leak them
+fn token_key(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_include_the_ones_before() {
+        assert!(Access::ReadOnly < Access::Operator);
+        assert!(Access::Operator < Access::Admin);
+    }
+
+    #[test]
+    fn roles_are_parsed_and_saved() {
+        assert_eq!("read".parse::<Access>().unwrap(), Access::ReadOnly);
+        assert_eq!("operator".parse::<Access>().unwrap(), Access::Operator);
+        assert_eq!("admin".parse::<Access>().unwrap(), Access::Admin);
+        assert_eq!("full".parse::<Access>().unwrap(), Access::Admin);
+        assert!("root".parse::<Access>().is_err());
+
+        // Saved before there were roles, 1 was full access
+        assert_eq!(Access::from_byte(1), Some(Access::Admin));
+        assert_eq!(Access::from_byte(3), None);
+
+        for access in [Access::ReadOnly, Access::Operator, Access::Admin].iter() {
+            assert_eq!(Access::from_byte(access.to_byte()), Some(*access));
+        }
+    }
+}
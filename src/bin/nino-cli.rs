@@ -0,0 +1,680 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use prost::Message;
+use serde_json::json;
+
+use nino::client::{self, Client, Options};
+use nino::discovery::{self, Instance};
+use nino::protocol::{proto, sensor_name, MessageId};
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let sensor_id = || {
+        Arg::new("id")
+            .about("The id of the sensor")
+            .required(true)
+            .takes_value(true)
+    };
+
+    let matches = App::new("nino-cli")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Script against a Nino server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .args(client::args())
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .about("Print JSON, one document per line"),
+        )
+        .subcommand(
+            App::new("discover")
+                .about("List the Nino servers on the local network")
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .about("Seconds to wait for answers, defaults to 2")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(App::new("list").about("List all sensors with their latest value"))
+        .subcommand(
+            App::new("graph").about("Print which sensors virtual sensors read, as Graphviz dot"),
+        )
+        .subcommand(App::new("watch").about("Print values from all sensors as they arrive"))
+        .subcommand(
+            App::new("tail")
+                .about("Print values from one sensor as they arrive")
+                .arg(sensor_id()),
+        )
+        .subcommand(
+            App::new("history")
+                .about("Dump the values the server retained for a sensor, oldest first")
+                .arg(sensor_id()),
+        )
+        .subcommand(
+            App::new("pwm")
+                .about("Set the duty cycle of a pwm channel")
+                .arg(
+                    Arg::new("channel")
+                        .about("The channel, 0 or 1")
+                        .required(true)
+                        .possible_values(&["0", "1"]),
+                )
+                .arg(
+                    Arg::new("value")
+                        .about("Duty cycle between 0.0 and 1.0")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("peer")
+                        .long("peer")
+                        .about("Instance name of a federated peer the channel belongs to")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("add")
+                .about("Add a virtual sensor and print its id")
+                .arg(
+                    Arg::new("external")
+                        .long("external")
+                        .about("Add an external sensor that values are pushed to instead"),
+                ),
+        )
+        .subcommand(
+            App::new("push")
+                .about("Push a value to an external sensor, negative values are fine")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(sensor_id())
+                .arg(Arg::new("value").required(true)),
+        )
+        .subcommand(
+            App::new("config")
+                .about("Configure a sensor, options that are left out keep their value")
+                .arg(sensor_id())
+                .arg(Arg::new("alias").long("alias").takes_value(true))
+                .arg(Arg::new("unit").long("unit").takes_value(true))
+                .arg(
+                    Arg::new("rate")
+                        .long("rate")
+                        .about("Milliseconds between evaluations of a virtual sensor, the least for change triggers")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("source-file")
+                        .long("source-file")
+                        .about("File with the Rhai source of a virtual sensor")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("trigger")
+                        .long("trigger")
+                        .about("Evaluate a virtual sensor when what it reads changes, every rate ms or both")
+                        .takes_value(true)
+                        .possible_values(&["change", "interval", "both"]),
+                ),
+        )
+        .subcommand(
+            App::new("validate")
+                .about("Run a virtual sensor source once without saving it")
+                .arg(Arg::new("source-file").required(true))
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .about("The virtual sensor the source is meant for")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("remove")
+                .about("Remove a virtual or external sensor")
+                .arg(sensor_id()),
+        )
+        .subcommand(
+            App::new("module")
+                .about("Manage the Rhai modules virtual sensors can import")
+                .subcommand(App::new("list").about("List the modules and what imports them"))
+                .subcommand(
+                    App::new("show")
+                        .about("Print the source of a module")
+                        .arg(Arg::new("name").required(true)),
+                )
+                .subcommand(
+                    App::new("set")
+                        .about("Add or replace a module, the sensors importing it are recompiled")
+                        .arg(Arg::new("name").required(true))
+                        .arg(Arg::new("source-file").required(true)),
+                )
+                .subcommand(
+                    App::new("remove")
+                        .about("Remove a module")
+                        .arg(Arg::new("name").required(true)),
+                ),
+        )
+        .get_matches();
+
+    let json = matches.is_present("json");
+
+    if let Some(("discover", sub)) = matches.subcommand() {
+        let timeout = Duration::from_secs(sub.value_of_t("timeout").unwrap_or(2));
+
+        for instance in discovery::browse(timeout)? {
+            print_instance(&instance, json);
+        }
+
+        return Ok(());
+    }
+
+    let options = Options::from_matches(
+        &matches,
+        vec![
+            proto::Capability::Acks,
+            proto::Capability::Deltas,
+            proto::Capability::Subscribe,
+            proto::Capability::Heartbeat,
+            proto::Capability::ValidateScript,
+            proto::Capability::Modules,
+        ],
+    )?;
+
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(run(&matches, options, json))
+}
+
+async fn run(matches: &ArgMatches, options: Options, json: bool) -> Result<()> {
+    let mut client = Client::connect(&options).await?;
+
+    match matches.subcommand() {
+        Some(("list", _)) => {
+            for sensor in client.sensors.iter() {
+                print_sensor(sensor, json);
+            }
+        }
+        Some(("graph", _)) => print_graph(&client.sensors, json),
+        Some(("watch", _)) => watch(&mut client, None, json).await?,
+        Some(("tail", sub)) => {
+            let id = sub.value_of_t("id")?;
+            find_sensor(&client, id)?;
+
+            if client.supports(proto::Capability::Subscribe) {
+                let sensor = proto::subscribe::Sensor {
+                    id,
+                    min_interval: 0,
+                    deadband: 0.0,
+                };
+                let subscribe = proto::Subscribe {
+                    sensors: vec![sensor],
+                    request_id: 0,
+                };
+
+                client
+                    .request(MessageId::Subscribe, |request_id| proto::Subscribe {
+                        request_id,
+                        ..subscribe
+                    })
+                    .await?;
+            }
+
+            watch(&mut client, Some(id), json).await?;
+        }
+        Some(("history", sub)) => {
+            let sensor = find_sensor(&client, sub.value_of_t("id")?)?;
+
+            // Values are stored newest first
+            let values: Vec<f64> = sensor.values.iter().rev().copied().collect();
+
+            if json {
+                println!("{}", json!({ "id": sensor.id, "values": values }));
+            } else {
+                for value in values {
+                    println!("{}", value);
+                }
+            }
+        }
+        Some(("pwm", sub)) => {
+            let channel: i32 = sub.value_of_t("channel")?;
+            let value: f32 = sub.value_of_t("value")?;
+            let instance = sub.value_of("peer").unwrap_or("").to_string();
+
+            client
+                .request(MessageId::Pwm, |request_id| proto::SetPwm {
+                    channel,
+                    value,
+                    instance,
+                    request_id,
+                })
+                .await?;
+        }
+        Some(("add", sub)) => {
+            let kind = match sub.is_present("external") {
+                true => proto::add_sensor::Kind::External,
+                false => proto::add_sensor::Kind::Virtual,
+            };
+
+            let ack = client
+                .request(MessageId::AddSensor, |request_id| proto::AddSensor {
+                    kind: kind as i32,
+                    request_id,
+                })
+                .await?;
+
+            let id = match ack.optional_sensor_id {
+                Some(proto::ack::OptionalSensorId::SensorId(id)) => id,
+                None => anyhow::bail!("Server did not say which sensor it added"),
+            };
+
+            if json {
+                println!("{}", json!({ "id": id }));
+            } else {
+                println!("{}", id);
+            }
+        }
+        Some(("config", sub)) => {
+            let sensor = find_sensor(&client, sub.value_of_t("id")?)?.clone();
+            let id = sensor.id;
+
+            let rate = match sub.value_of("rate") {
+                Some(rate) => Some(rate.parse()?),
+                None => Some(sensor.rate),
+            };
+
+            let trigger = match sub.value_of("trigger") {
+                Some("interval") => proto::Trigger::Interval,
+                Some("both") => proto::Trigger::Both,
+                Some(_) => proto::Trigger::Change,
+                None => sensor.trigger(),
+            };
+
+            let source = match sub.value_of("source-file") {
+                Some(path) => Some(std::fs::read_to_string(path)?),
+                None => sensor.optional_source.map(|s| match s {
+                    proto::sensors::sensor::OptionalSource::Source(s) => s,
+                }),
+            };
+
+            let alias = sub
+                .value_of("alias")
+                .map(Into::into)
+                .unwrap_or(sensor.alias);
+            let unit = sub.value_of("unit").map(Into::into).unwrap_or(sensor.unit);
+
+            client
+                .request(MessageId::SensorConfig, |request_id| proto::SensorConfig {
+                    id,
+                    alias,
+                    unit,
+                    optional_rate: rate.map(proto::sensor_config::OptionalRate::Rate),
+                    optional_source: source.map(proto::sensor_config::OptionalSource::Source),
+                    optional_trigger: Some(proto::sensor_config::OptionalTrigger::Trigger(
+                        trigger as i32,
+                    )),
+                    request_id,
+                })
+                .await?;
+        }
+        Some(("push", sub)) => {
+            let id = sub.value_of_t("id")?;
+            let value = sub.value_of_t("value")?;
+
+            client
+                .request(MessageId::PushValue, |request_id| proto::PushValue {
+                    id,
+                    value,
+                    request_id,
+                })
+                .await?;
+        }
+        Some(("validate", sub)) => {
+            if !client.supports(proto::Capability::ValidateScript) {
+                anyhow::bail!("Server can not validate scripts");
+            }
+
+            let source = std::fs::read_to_string(sub.value_of("source-file").unwrap())?;
+            let id = match sub.value_of("id") {
+                Some(id) => Some(proto::validate_script::OptionalId::Id(id.parse()?)),
+                None => None,
+            };
+
+            let request_id = client.request_id();
+            let validate = proto::ValidateScript {
+                source,
+                optional_id: id,
+                request_id,
+            };
+            client.send(MessageId::ValidateScript, validate).await?;
+
+            let result = loop {
+                match client.receive().await? {
+                    (MessageId::ScriptResult, data) => {
+                        let result = proto::ScriptResult::decode(data.as_slice())?;
+                        if result.request_id == request_id {
+                            break result;
+                        }
+                    }
+                    (MessageId::Error, data) => {
+                        let error = proto::Error::decode(data.as_slice())?;
+                        if error.request_id == request_id {
+                            anyhow::bail!("{}", error.message);
+                        }
+                    }
+                    _ => {}
+                }
+            };
+
+            print_script_result(&result, json);
+
+            if !result.errors.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Some(("module", sub)) => {
+            if !client.supports(proto::Capability::Modules) {
+                anyhow::bail!("Server has no module library");
+            }
+
+            match sub.subcommand() {
+                Some(("set", sub)) => {
+                    let name = sub.value_of("name").unwrap().into();
+                    let source = std::fs::read_to_string(sub.value_of("source-file").unwrap())?;
+
+                    client
+                        .request(MessageId::SetModule, |request_id| proto::SetModule {
+                            name,
+                            source,
+                            request_id,
+                        })
+                        .await?;
+                }
+                Some(("remove", sub)) => {
+                    let name = sub.value_of("name").unwrap().into();
+
+                    client
+                        .request(MessageId::RemoveModule, |request_id| {
+                            proto::RemoveModule { name, request_id }
+                        })
+                        .await?;
+                }
+                Some(("show", sub)) => {
+                    let name = sub.value_of("name").unwrap();
+                    let modules = list_modules(&mut client).await?;
+
+                    match modules.iter().find(|m| m.name == name) {
+                        Some(module) if json => print_module(module, json),
+                        Some(module) => print!("{}", module.source),
+                        None => anyhow::bail!("No module {}", name),
+                    }
+                }
+                _ => {
+                    for module in list_modules(&mut client).await? {
+                        print_module(&module, json);
+                    }
+                }
+            }
+        }
+        Some(("remove", sub)) => {
+            let id = sub.value_of_t("id")?;
+
+            client
+                .request(MessageId::RemoveSensor, |request_id| proto::RemoveSensor {
+                    id,
+                    request_id,
+                })
+                .await?;
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+
+    Ok(())
+}
+
+/// Print values as they arrive, from all sensors or just one
+async fn watch(client: &mut Client, only: Option<u32>, json: bool) -> Result<()> {
+    let mut sensors: HashMap<u32, proto::sensors::Sensor> =
+        client.sensors.drain(..).map(|s| (s.id, s)).collect();
+
+    loop {
+        match client.receive().await? {
+            (MessageId::Value, data) => {
+                let value = proto::Value::decode(data.as_slice())?;
+
+                if only.map(|id| id != value.id).unwrap_or(false) {
+                    continue;
+                }
+
+                let (alias, unit) = match sensors.get(&value.id) {
+                    Some(s) => (sensor_name(s), s.unit.as_str()),
+                    None => ("?".into(), ""),
+                };
+
+                if json {
+                    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+                    println!(
+                        "{}",
+                        json!({
+                            "id": value.id,
+                            "alias": alias,
+                            "unit": unit,
+                            "value": value.value,
+                            "time": time,
+                        })
+                    );
+                } else {
+                    println!(
+                        "{:>3} {:<16} {:>10.2} {}",
+                        value.id, alias, value.value, unit
+                    );
+                }
+            }
+            (MessageId::Sensors, data) => {
+                sensors = proto::Sensors::decode(data.as_slice())?
+                    .sensors
+                    .into_iter()
+                    .map(|s| (s.id, s))
+                    .collect();
+            }
+            (MessageId::SensorChanged, data) => {
+                if let Some(sensor) = proto::SensorChanged::decode(data.as_slice())?.sensor {
+                    sensors.insert(sensor.id, sensor);
+                }
+            }
+            (MessageId::SensorRemoved, data) => {
+                let removed = proto::SensorRemoved::decode(data.as_slice())?;
+                sensors.remove(&removed.id);
+
+                if only == Some(removed.id) {
+                    anyhow::bail!("Sensor {} was removed", removed.id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn find_sensor(client: &Client, id: u32) -> Result<&proto::sensors::Sensor> {
+    client
+        .sensors
+        .iter()
+        .find(|s| s.id == id)
+        .ok_or_else(|| anyhow::format_err!("No sensor {}", id))
+}
+
+fn print_graph(sensors: &[proto::sensors::Sensor], json: bool) {
+    let edges: Vec<(u32, u32)> = sensors
+        .iter()
+        .flat_map(|s| s.dependencies.iter().map(move |dep| (*dep, s.id)))
+        .collect();
+
+    if json {
+        let nodes: Vec<_> = sensors
+            .iter()
+            .map(|s| json!({ "id": s.id, "name": sensor_name(s) }))
+            .collect();
+
+        println!("{}", json!({ "nodes": nodes, "edges": edges }));
+        return;
+    }
+
+    println!("digraph nino {{");
+    for sensor in sensors {
+        println!("    {} [label={:?}];", sensor.id, sensor_name(sensor));
+    }
+    for (from, to) in edges {
+        println!("    {} -> {};", from, to);
+    }
+    println!("}}");
+}
+
+async fn list_modules(client: &mut Client) -> Result<Vec<proto::modules::Module>> {
+    let request_id = client.request_id();
+    client
+        .send(MessageId::ListModules, proto::ListModules { request_id })
+        .await?;
+
+    loop {
+        match client.receive().await? {
+            (MessageId::Modules, data) => {
+                let modules = proto::Modules::decode(data.as_slice())?;
+                if modules.request_id == request_id {
+                    return Ok(modules.modules);
+                }
+            }
+            (MessageId::Error, data) => {
+                let error = proto::Error::decode(data.as_slice())?;
+                if error.request_id == request_id {
+                    anyhow::bail!("{}", error.message);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn print_module(module: &proto::modules::Module, json: bool) {
+    if json {
+        println!(
+            "{}",
+            json!({
+                "name": module.name,
+                "source": module.source,
+                "dependents": module.dependents,
+            })
+        );
+        return;
+    }
+
+    let dependents: Vec<String> = module.dependents.iter().map(u32::to_string).collect();
+    println!("{:<16} {}", module.name, dependents.join(", "));
+}
+
+fn print_script_result(result: &proto::ScriptResult, json: bool) {
+    let value = result.optional_value.as_ref().map(|v| match v {
+        proto::script_result::OptionalValue::Value(v) => *v,
+    });
+
+    if json {
+        let errors: Vec<_> = result
+            .errors
+            .iter()
+            .map(|e| json!({ "message": e.message, "line": e.line, "column": e.column }))
+            .collect();
+
+        println!(
+            "{}",
+            json!({
+                "value": value,
+                "dependencies": result.dependencies,
+                "errors": errors,
+                "eval_time": result.eval_time,
+            })
+        );
+        return;
+    }
+
+    if let Some(value) = value {
+        println!("value        {}", value);
+    }
+
+    for e in result.errors.iter() {
+        match e.line {
+            0 => println!("error        {}", e.message),
+            _ => println!("error        {}:{} {}", e.line, e.column, e.message),
+        }
+    }
+
+    let dependencies: Vec<String> = result.dependencies.iter().map(u32::to_string).collect();
+    println!("dependencies {}", dependencies.join(", "));
+    println!("eval time    {} us", result.eval_time);
+}
+
+fn print_sensor(sensor: &proto::sensors::Sensor, json: bool) {
+    let value = sensor.values.first().copied();
+    let error = sensor.optional_error.as_ref().map(|e| match e {
+        proto::sensors::sensor::OptionalError::Error(e) => e.as_str(),
+    });
+
+    if json {
+        println!(
+            "{}",
+            json!({
+                "id": sensor.id,
+                "alias": sensor.alias,
+                "instance": sensor.instance,
+                "unit": sensor.unit,
+                "rate": sensor.rate,
+                "value": value,
+                "error": error,
+                "dependencies": sensor.dependencies,
+                "trigger": format!("{:?}", sensor.trigger()).to_lowercase(),
+            })
+        );
+        return;
+    }
+
+    let value = match value {
+        Some(v) => format!("{:.2}", v),
+        None => "-".into(),
+    };
+
+    print!(
+        "{:>3} {:<16} {:>10} {}",
+        sensor.id,
+        sensor_name(sensor),
+        value,
+        sensor.unit
+    );
+
+    match error {
+        Some(e) => println!("  ERROR {}", e),
+        None => println!(),
+    }
+}
+
+fn print_instance(instance: &Instance, json: bool) {
+    let address = instance.address();
+
+    if json {
+        println!(
+            "{}",
+            json!({
+                "name": instance.name,
+                "address": address,
+                "version": instance.version,
+                "protocol_version": instance.protocol_version,
+                "capabilities": instance.capabilities,
+            })
+        );
+        return;
+    }
+
+    println!(
+        "{:<16} {:<24} v{} {}",
+        instance.name,
+        address.as_deref().unwrap_or("-"),
+        instance.version,
+        instance.capabilities.join(",")
+    );
+}
@@ -0,0 +1,617 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Stdout;
+
+use anyhow::Result;
+use clap::App;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use prost::Message;
+use tokio::sync::mpsc;
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState};
+use tui::{Frame, Terminal};
+
+use nino::client::{self, Client, Options};
+use nino::protocol::{proto, sensor_name, MessageId};
+
+type Backend = CrosstermBackend<Stdout>;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const SPARK_WIDTH: usize = 30;
+const PWM_STEP: f32 = 0.05;
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let matches = App::new("nino-tui")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Monitor and control a Nino server from the terminal")
+        .args(client::args())
+        .get_matches();
+
+    let options = Options::from_matches(
+        &matches,
+        vec![
+            proto::Capability::Acks,
+            proto::Capability::Deltas,
+            proto::Capability::Heartbeat,
+        ],
+    )?;
+
+    let rt = tokio::runtime::Runtime::new()?;
+    let client = rt.block_on(Client::connect(&options))?;
+
+    crossterm::terminal::enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+    terminal.hide_cursor()?;
+
+    let res = rt.block_on(run(&mut terminal, client));
+
+    // Give the terminal back even if the connection failed
+    crossterm::terminal::disable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    // The input thread is blocked reading the terminal and would keep the runtime alive
+    rt.shutdown_background();
+
+    res
+}
+
+async fn run(terminal: &mut Terminal<Backend>, mut client: Client) -> Result<()> {
+    let (tx, mut input) = mpsc::channel(16);
+
+    // Reading the terminal blocks, so it gets a thread of its own
+    std::thread::Builder::new()
+        .name("input".into())
+        .spawn(move || loop {
+            let event = crossterm::event::read();
+            let failed = event.is_err();
+            if tx.blocking_send(event).is_err() || failed {
+                return;
+            }
+        })?;
+
+    let mut ui = Ui::new(&mut client);
+
+    loop {
+        terminal.draw(|f| ui.draw(f))?;
+
+        tokio::select! {
+            res = client.receive() => {
+                let (id, data) = res?;
+                ui.handle_message(id, &data)?;
+            }
+            event = input.recv() => {
+                let event = match event {
+                    Some(event) => event?,
+                    None => return Ok(()),
+                };
+
+                if let Event::Key(key) = event {
+                    match ui.handle_key(key) {
+                        Action::Nothing => {}
+                        Action::Quit => return Ok(()),
+                        Action::SetPwm(channel, value) => {
+                            let request_id = client.request_id();
+                            ui.pending.insert(request_id, format!("pwm{} {:.0}%", channel, value * 100.0));
+
+                            let pwm = proto::SetPwm { channel, value, instance: String::new(), request_id };
+                            client.send(MessageId::Pwm, pwm).await?;
+                        }
+                        Action::Save(mut config) => {
+                            config.request_id = client.request_id();
+                            ui.pending.insert(config.request_id, format!("sensor {}", config.id));
+
+                            if let Some(ref mut editor) = ui.editor {
+                                editor.pending = Some(config.request_id);
+                            }
+
+                            client.send(MessageId::SensorConfig, config).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Action {
+    Nothing,
+    Quit,
+    SetPwm(i32, f32),
+    Save(proto::SensorConfig),
+}
+
+struct Ui {
+    name: String,
+    version: String,
+    role: Option<proto::welcome::Role>,
+    acks: bool,
+    retention: usize,
+    sensors: BTreeMap<u32, proto::sensors::Sensor>,
+    table: TableState,
+    pwm: [f32; 2],
+    editor: Option<Editor>,
+    status: String,
+    /// Requests waiting for an ack, with a description for the status line
+    pending: HashMap<u32, String>,
+}
+
+impl Ui {
+    fn new(client: &mut Client) -> Ui {
+        let mut table = TableState::default();
+        if !client.sensors.is_empty() {
+            table.select(Some(0));
+        }
+
+        Ui {
+            name: client.hello.name.clone(),
+            version: client.hello.version.clone(),
+            role: client
+                .welcome
+                .as_ref()
+                .and_then(|w| proto::welcome::Role::from_i32(w.role)),
+            acks: client.supports(proto::Capability::Acks),
+            retention: client.hello.retention as usize,
+            sensors: client.sensors.drain(..).map(|s| (s.id, s)).collect(),
+            table,
+            pwm: [client.hello.pwm0, client.hello.pwm1],
+            editor: None,
+            status: String::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn selected(&self) -> Option<&proto::sensors::Sensor> {
+        self.table
+            .selected()
+            .and_then(|i| self.sensors.values().nth(i))
+    }
+
+    fn handle_message(&mut self, id: MessageId, data: &[u8]) -> Result<()> {
+        match id {
+            MessageId::Value => {
+                let value = proto::Value::decode(data)?;
+
+                if let Some(sensor) = self.sensors.get_mut(&value.id) {
+                    sensor.values.insert(0, value.value);
+                    sensor.values.truncate(self.retention.max(SPARK_WIDTH));
+                }
+            }
+            MessageId::Sensors => {
+                self.sensors = proto::Sensors::decode(data)?
+                    .sensors
+                    .into_iter()
+                    .map(|s| (s.id, s))
+                    .collect();
+            }
+            MessageId::SensorChanged => {
+                if let Some(mut sensor) = proto::SensorChanged::decode(data)?.sensor {
+                    // Changes leave out the values
+                    if let Some(old) = self.sensors.remove(&sensor.id) {
+                        sensor.values = old.values;
+                    }
+                    self.sensors.insert(sensor.id, sensor);
+                }
+            }
+            MessageId::SensorError => {
+                let error = proto::SensorError::decode(data)?;
+
+                if let Some(sensor) = self.sensors.get_mut(&error.id) {
+                    sensor.optional_error = error.optional_error.map(|e| match e {
+                        proto::sensor_error::OptionalError::Error(e) => {
+                            proto::sensors::sensor::OptionalError::Error(e)
+                        }
+                    });
+                }
+            }
+            MessageId::SensorRemoved => {
+                let removed = proto::SensorRemoved::decode(data)?;
+                self.sensors.remove(&removed.id);
+            }
+            MessageId::Ack => {
+                let ack = proto::Ack::decode(data)?;
+
+                if let Some(what) = self.pending.remove(&ack.request_id) {
+                    self.status = format!("Applied {}", what);
+                }
+
+                if let Some(true) = self
+                    .editor
+                    .as_ref()
+                    .map(|e| e.pending == Some(ack.request_id))
+                {
+                    self.editor = None;
+                }
+            }
+            MessageId::Error => {
+                let error = proto::Error::decode(data)?;
+                let code =
+                    proto::error::Code::from_i32(error.code).unwrap_or(proto::error::Code::Unknown);
+                let message = format!("{:?}: {}", code, error.message);
+
+                self.status = match self.pending.remove(&error.request_id) {
+                    Some(what) => format!("Failed {}, {}", what, message),
+                    None => message.clone(),
+                };
+
+                if let Some(ref mut editor) = self.editor {
+                    if editor.pending.is_some() && editor.pending == Some(error.request_id) {
+                        editor.pending = None;
+                        editor.error = Some(message);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // Keep the selection inside the table when sensors are removed
+        match (self.table.selected(), self.sensors.len()) {
+            (_, 0) => self.table.select(None),
+            (None, _) => self.table.select(Some(0)),
+            (Some(i), len) if i >= len => self.table.select(Some(len - 1)),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Action::Quit;
+        }
+
+        if self.editor.is_some() {
+            return self.handle_editor_key(key);
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Action::Quit,
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Char('[') => return self.step_pwm(0, -PWM_STEP),
+            KeyCode::Char(']') => return self.step_pwm(0, PWM_STEP),
+            KeyCode::Char('{') => return self.step_pwm(1, -PWM_STEP),
+            KeyCode::Char('}') => return self.step_pwm(1, PWM_STEP),
+            KeyCode::Char('e') | KeyCode::Enter => {
+                let source = self.selected().map(|s| (s.id, s.optional_source.clone()));
+
+                match source {
+                    Some((id, Some(proto::sensors::sensor::OptionalSource::Source(source)))) => {
+                        self.editor = Some(Editor::new(id, &source));
+                    }
+                    Some(_) => self.status = "Only virtual sensors have a source".into(),
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+
+        Action::Nothing
+    }
+
+    fn handle_editor_key(&mut self, key: KeyEvent) -> Action {
+        let editor = match self.editor {
+            Some(ref mut editor) => editor,
+            None => return Action::Nothing,
+        };
+
+        if key.code == KeyCode::Esc {
+            self.editor = None;
+            return Action::Nothing;
+        }
+
+        if key.code == KeyCode::Char('s') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            if !self.acks {
+                editor.error = Some("Server does not acknowledge requests".into());
+                return Action::Nothing;
+            }
+
+            let sensor = match self.sensors.get(&editor.id) {
+                Some(sensor) => sensor,
+                None => {
+                    editor.error = Some("The sensor was removed".into());
+                    return Action::Nothing;
+                }
+            };
+
+            editor.error = None;
+
+            return Action::Save(proto::SensorConfig {
+                id: sensor.id,
+                alias: sensor.alias.clone(),
+                unit: sensor.unit.clone(),
+                optional_rate: Some(proto::sensor_config::OptionalRate::Rate(sensor.rate)),
+                optional_source: Some(proto::sensor_config::OptionalSource::Source(
+                    editor.source(),
+                )),
+                optional_trigger: None,
+                request_id: 0,
+            });
+        }
+
+        editor.handle_key(key);
+
+        Action::Nothing
+    }
+
+    fn move_selection(&mut self, by: isize) {
+        if self.sensors.is_empty() {
+            return;
+        }
+
+        let last = self.sensors.len() as isize - 1;
+        let i = self.table.selected().map(|i| i as isize).unwrap_or(0) + by;
+        self.table.select(Some(i.max(0).min(last) as usize));
+    }
+
+    fn step_pwm(&mut self, channel: usize, by: f32) -> Action {
+        if !self.acks {
+            self.status = "Server does not acknowledge requests".into();
+            return Action::Nothing;
+        }
+
+        let value = (self.pwm[channel] + by).clamp(0.0, 1.0);
+        self.pwm[channel] = value;
+
+        Action::SetPwm(channel as i32, value)
+    }
+
+    fn draw(&mut self, f: &mut Frame<Backend>) {
+        let constraints = match self.editor {
+            Some(_) => vec![
+                Constraint::Length(1),
+                Constraint::Percentage(50),
+                Constraint::Min(5),
+                Constraint::Length(1),
+            ],
+            None => vec![
+                Constraint::Length(1),
+                Constraint::Min(3),
+                Constraint::Length(1),
+            ],
+        };
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(constraints)
+            .split(f.size());
+
+        let role = match self.role {
+            Some(role) => format!("{:?}", role),
+            None => "-".into(),
+        };
+
+        let header = Spans::from(vec![
+            Span::styled(&self.name, Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(format!(
+                "  v{}  role {}  pwm0 {:.0}%  pwm1 {:.0}%",
+                self.version,
+                role,
+                self.pwm[0] * 100.0,
+                self.pwm[1] * 100.0
+            )),
+        ]);
+        f.render_widget(Paragraph::new(header), chunks[0]);
+
+        self.draw_sensors(f, chunks[1]);
+
+        if let Some(ref editor) = self.editor {
+            editor.draw(f, chunks[2]);
+        }
+
+        let help = match self.editor {
+            Some(_) => "ctrl-s save  esc cancel",
+            None => "q quit  ↑↓ select  e edit source  [ ] pwm0  { } pwm1",
+        };
+
+        let status = Spans::from(vec![
+            Span::styled(help, Style::default().fg(Color::DarkGray)),
+            Span::raw("  "),
+            Span::raw(&self.status),
+        ]);
+        f.render_widget(Paragraph::new(status), chunks[chunks.len() - 1]);
+    }
+
+    fn draw_sensors(&mut self, f: &mut Frame<Backend>, area: Rect) {
+        let rows: Vec<Row> = self
+            .sensors
+            .values()
+            .map(|s| {
+                let value = match s.values.first() {
+                    Some(v) => format!("{:.2}", v),
+                    None => "-".into(),
+                };
+
+                let error = match s.optional_error {
+                    Some(proto::sensors::sensor::OptionalError::Error(ref e)) => Cell::from(
+                        Span::styled(format!(" ERR {} ", e), Style::default().bg(Color::Red)),
+                    ),
+                    None => Cell::from(""),
+                };
+
+                Row::new(vec![
+                    Cell::from(s.id.to_string()),
+                    Cell::from(sensor_name(s)),
+                    Cell::from(value),
+                    Cell::from(s.unit.as_str()),
+                    Cell::from(Span::styled(
+                        sparkline(&s.values, SPARK_WIDTH),
+                        Style::default().fg(Color::Cyan),
+                    )),
+                    error,
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Length(3),
+            Constraint::Length(16),
+            Constraint::Length(10),
+            Constraint::Length(5),
+            Constraint::Length(SPARK_WIDTH as u16),
+            Constraint::Min(10),
+        ];
+
+        let table = Table::new(rows)
+            .header(
+                Row::new(vec!["Id", "Alias", "Value", "Unit", "History", ""])
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+            )
+            .block(Block::default().borders(Borders::ALL).title("Sensors"))
+            .widths(&widths)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+        f.render_stateful_widget(table, area, &mut self.table);
+    }
+}
+
+/// Values newest first as a line of bars, oldest to the left
+fn sparkline(values: &[f64], width: usize) -> String {
+    let values = &values[..values.len().min(width)];
+
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let top = (SPARKS.len() - 1) as f64;
+
+    values
+        .iter()
+        .rev()
+        .map(|v| match max - min {
+            range if range > 0.0 => SPARKS[((v - min) / range * top).round() as usize],
+            _ => SPARKS[SPARKS.len() / 2],
+        })
+        .collect()
+}
+
+/// A minimal multi line editor for the Rhai source of a virtual sensor
+struct Editor {
+    id: u32,
+    lines: Vec<String>,
+    row: usize,
+    col: usize, // In chars, not bytes
+    /// The request the source was sent in, the editor closes when it is acked
+    pending: Option<u32>,
+    error: Option<String>,
+}
+
+impl Editor {
+    fn new(id: u32, source: &str) -> Editor {
+        let mut lines: Vec<String> = source.lines().map(Into::into).collect();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+
+        Editor {
+            id,
+            lines,
+            row: 0,
+            col: 0,
+            pending: None,
+            error: None,
+        }
+    }
+
+    fn source(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    fn byte_col(&self) -> usize {
+        let line = &self.lines[self.row];
+        line.char_indices()
+            .nth(self.col)
+            .map(|(i, _)| i)
+            .unwrap_or_else(|| line.len())
+    }
+
+    fn line_len(&self, row: usize) -> usize {
+        self.lines[row].chars().count()
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Char(c) => {
+                let at = self.byte_col();
+                self.lines[self.row].insert(at, c);
+                self.col += 1;
+            }
+            KeyCode::Tab => {
+                let at = self.byte_col();
+                self.lines[self.row].insert_str(at, "    ");
+                self.col += 4;
+            }
+            KeyCode::Enter => {
+                let at = self.byte_col();
+                let rest = self.lines[self.row].split_off(at);
+                self.row += 1;
+                self.col = 0;
+                self.lines.insert(self.row, rest);
+            }
+            KeyCode::Backspace if self.col > 0 => {
+                self.col -= 1;
+                let at = self.byte_col();
+                self.lines[self.row].remove(at);
+            }
+            KeyCode::Backspace if self.row > 0 => {
+                let line = self.lines.remove(self.row);
+                self.row -= 1;
+                self.col = self.line_len(self.row);
+                self.lines[self.row].push_str(&line);
+            }
+            KeyCode::Delete if self.col < self.line_len(self.row) => {
+                let at = self.byte_col();
+                self.lines[self.row].remove(at);
+            }
+            KeyCode::Delete if self.row + 1 < self.lines.len() => {
+                let line = self.lines.remove(self.row + 1);
+                self.lines[self.row].push_str(&line);
+            }
+            KeyCode::Left if self.col > 0 => self.col -= 1,
+            KeyCode::Right if self.col < self.line_len(self.row) => self.col += 1,
+            KeyCode::Up if self.row > 0 => {
+                self.row -= 1;
+                self.col = self.col.min(self.line_len(self.row));
+            }
+            KeyCode::Down if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.col = self.col.min(self.line_len(self.row));
+            }
+            KeyCode::Home => self.col = 0,
+            KeyCode::End => self.col = self.line_len(self.row),
+            _ => {}
+        }
+    }
+
+    fn draw(&self, f: &mut Frame<Backend>, area: Rect) {
+        let title = match (&self.error, self.pending) {
+            (Some(e), _) => Span::styled(
+                format!("Sensor {} source, {}", self.id, e),
+                Style::default().fg(Color::Red),
+            ),
+            (None, Some(_)) => Span::raw(format!("Sensor {} source, saving...", self.id)),
+            (None, None) => Span::raw(format!("Sensor {} source", self.id)),
+        };
+
+        // Scroll so the cursor stays in view
+        let height = area.height.saturating_sub(2) as usize;
+        let scroll = (self.row + 1).saturating_sub(height);
+
+        let text: Vec<Spans> = self.lines.iter().map(|l| Spans::from(l.as_str())).collect();
+
+        let paragraph = Paragraph::new(text)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .scroll((scroll as u16, 0));
+
+        f.render_widget(paragraph, area);
+        f.set_cursor(
+            area.x + 1 + self.col as u16,
+            area.y + 1 + (self.row - scroll) as u16,
+        );
+    }
+}
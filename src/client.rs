@@ -0,0 +1,372 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{Arg, ArgMatches};
+use hmac::{Hmac, Mac, NewMac};
+use prost::Message;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+
+use crate::discovery;
+use crate::pem::{load_certs, load_key};
+use crate::protocol::{proto, receive_package, send_package, MessageId, PROTOCOL_VERSION};
+
+/// Everything a client needs to connect to a nino server
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// host:port of the server
+    pub address: String,
+    /// Shared secret used to sign the hello nonce
+    pub secret: Option<Vec<u8>>,
+    /// Token stored on the server, used if there is no secret
+    pub token: Option<String>,
+    pub tls: Option<TlsOptions>,
+    /// The capabilities the client wants, the server answers with the ones it agrees to
+    pub capabilities: Vec<proto::Capability>,
+}
+
+impl Options {
+    /// The options given with `args()` on the command line. An instance is found with
+    /// mDNS, its host name is the default TLS server name
+    pub fn from_matches(
+        matches: &ArgMatches,
+        capabilities: Vec<proto::Capability>,
+    ) -> Result<Options> {
+        let (address, server_name) = match matches.value_of("instance") {
+            Some(name) => {
+                let instance = discovery::resolve(name, Duration::from_secs(3))?;
+                let address = instance
+                    .address()
+                    .ok_or_else(|| anyhow::format_err!("{} did not advertise an address", name))?;
+                (address, Some(instance.hostname))
+            }
+            None => (matches.value_of("host").unwrap_or("127.0.0.1:7583").into(), None),
+        };
+        let server_name = matches.value_of("tls-server-name").map(Into::into).or(server_name);
+
+        Ok(Options {
+            address,
+            secret: match matches.value_of("secret-file") {
+                Some(path) => Some(std::fs::read_to_string(path)?.trim().as_bytes().to_vec()),
+                None => None,
+            },
+            token: matches.value_of("token").map(Into::into),
+            tls: matches.value_of("tls-ca").map(|ca| TlsOptions {
+                ca: ca.into(),
+                cert: matches.value_of("tls-cert").map(Into::into),
+                key: matches.value_of("tls-key").map(Into::into),
+                server_name,
+            }),
+            capabilities,
+        })
+    }
+}
+
+/// The arguments a command line client takes to find and authenticate with a server
+pub fn args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("host")
+            .long("host")
+            .short('H')
+            .about("host:port of the Nino server, defaults to 127.0.0.1:7583")
+            .env("NINO_HOST")
+            .takes_value(true),
+        Arg::new("instance")
+            .long("instance")
+            .short('i')
+            .about("Find the Nino server by its instance name with mDNS instead")
+            .env("NINO_INSTANCE")
+            .conflicts_with("host")
+            .takes_value(true),
+        Arg::new("secret-file")
+            .long("secret-file")
+            .about("File with the shared secret of the server")
+            .takes_value(true),
+        Arg::new("token")
+            .long("token")
+            .about("Token to authenticate with")
+            .env("NINO_TOKEN")
+            .takes_value(true),
+        Arg::new("tls-ca")
+            .long("tls-ca")
+            .about("Connect with TLS, verifying the server against these PEM CA certificates")
+            .takes_value(true),
+        Arg::new("tls-cert")
+            .long("tls-cert")
+            .about("PEM client certificate")
+            .requires_all(&["tls-ca", "tls-key"])
+            .takes_value(true),
+        Arg::new("tls-key")
+            .long("tls-key")
+            .about("PEM private key for the client certificate")
+            .requires("tls-cert")
+            .takes_value(true),
+        Arg::new("tls-server-name")
+            .long("tls-server-name")
+            .about("Name the server certificate is issued for, defaults to the host or mDNS host name")
+            .requires("tls-ca")
+            .takes_value(true),
+    ]
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM CA certificates the server certificate is verified against
+    pub ca: String,
+    /// PEM certificate and key for client certificate authentication
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// Name the server certificate is verified against, defaults to the host of the
+    /// address. Needed when connecting to an IP address
+    pub server_name: Option<String>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// A connection to a nino server that has finished the handshake
+pub struct Client {
+    pub hello: proto::Hello,
+    /// Only sent by servers that negotiate the protocol
+    pub welcome: Option<proto::Welcome>,
+    /// The sensors as they were when the client connected
+    pub sensors: Vec<proto::sensors::Sensor>,
+
+    incoming: mpsc::Receiver<Result<(MessageId, Vec<u8>)>>,
+    wrt: Arc<Mutex<Writer>>,
+    reader: JoinHandle<()>,
+    next_request_id: u32,
+}
+
+type Writer = BufWriter<WriteHalf<Box<dyn Stream>>>;
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Client {
+    pub async fn connect(options: &Options) -> Result<Client> {
+        let tcp = TcpStream::connect(&options.address).await?;
+
+        let stream: Box<dyn Stream> = match options.tls {
+            Some(ref tls) => Box::new(connect_tls(&options.address, tls, tcp).await?),
+            None => Box::new(tcp),
+        };
+
+        let (rdr, wrt) = tokio::io::split(stream);
+        let wrt = Arc::new(Mutex::new(BufWriter::new(wrt)));
+        let (tx, incoming) = mpsc::channel(64);
+
+        let reader = tokio::spawn(read_packages(BufReader::new(rdr), wrt.clone(), tx));
+
+        let mut client = Client {
+            hello: Default::default(),
+            welcome: None,
+            sensors: vec![],
+            incoming,
+            wrt,
+            reader,
+            next_request_id: 1,
+        };
+
+        client.hello = match client.receive().await? {
+            (MessageId::Hello, data) => proto::Hello::decode(data.as_slice())?,
+            (id, _) => anyhow::bail!("Server sent {:?} instead of hello", id),
+        };
+
+        let hmac = match options.secret {
+            Some(ref secret) => {
+                let mut mac = Hmac::<Sha256>::new_varkey(secret)
+                    .map_err(|e| anyhow::format_err!("Invalid secret {:?}", e))?;
+                mac.update(&client.hello.nonce);
+                mac.finalize().into_bytes().to_vec()
+            }
+            None => vec![],
+        };
+
+        let ready = proto::Ready {
+            hmac,
+            token: options.token.clone().unwrap_or_default(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: options.capabilities.iter().map(|c| *c as i32).collect(),
+        };
+
+        client.send(MessageId::Ready, ready).await?;
+
+        loop {
+            match client.receive().await? {
+                (MessageId::Welcome, data) => {
+                    client.welcome = Some(proto::Welcome::decode(data.as_slice())?)
+                }
+                (MessageId::Sensors, data) => {
+                    client.sensors = proto::Sensors::decode(data.as_slice())?.sensors;
+                    break;
+                }
+                (id, _) => log::debug!("Ignoring {:?} during handshake", id),
+            }
+        }
+
+        Ok(client)
+    }
+
+    /// Was the capability agreed on in the handshake
+    pub fn supports(&self, capability: proto::Capability) -> bool {
+        match self.welcome {
+            Some(ref welcome) => welcome.capabilities.contains(&(capability as i32)),
+            None => false,
+        }
+    }
+
+    pub async fn send<P>(&mut self, id: MessageId, package: P) -> Result<()>
+    where
+        P: prost::Message,
+    {
+        send_package(&mut *self.wrt.lock().await, id, package).await
+    }
+
+    /// Receive the next message, pings are answered and unknown messages skipped.
+    /// Cancel safe, so it can be used in a select
+    pub async fn receive(&mut self) -> Result<(MessageId, Vec<u8>)> {
+        match self.incoming.recv().await {
+            Some(res) => res,
+            None => anyhow::bail!("Connection closed"),
+        }
+    }
+
+    /// Send a request and wait for its Ack, the request id is filled in by `build`.
+    /// Everything else received in the mean time is dropped
+    pub async fn request<P, F>(&mut self, id: MessageId, build: F) -> Result<proto::Ack>
+    where
+        P: prost::Message,
+        F: FnOnce(u32) -> P,
+    {
+        if !self.supports(proto::Capability::Acks) {
+            anyhow::bail!("Server does not acknowledge requests");
+        }
+
+        let request_id = self.request_id();
+        self.send(id, build(request_id)).await?;
+
+        loop {
+            match self.receive().await? {
+                (MessageId::Ack, data) => {
+                    let ack = proto::Ack::decode(data.as_slice())?;
+                    if ack.request_id == request_id {
+                        return Ok(ack);
+                    }
+                }
+                (MessageId::Error, data) => {
+                    let error = proto::Error::decode(data.as_slice())?;
+                    if error.request_id == request_id {
+                        let code = proto::error::Code::from_i32(error.code)
+                            .unwrap_or(proto::error::Code::Unknown);
+                        anyhow::bail!("{:?}: {}", code, error.message);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// A fresh request id, for requests that are not answered with an Ack
+    pub fn request_id(&mut self) -> u32 {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1).max(1);
+        request_id
+    }
+}
+
+/// Reads packages off the socket on its own task, a read that is cut short would
+/// leave the stream in the middle of a package
+async fn read_packages<T>(
+    mut rdr: T,
+    wrt: Arc<Mutex<Writer>>,
+    tx: mpsc::Sender<Result<(MessageId, Vec<u8>)>>,
+) where
+    T: AsyncRead + Unpin,
+{
+    loop {
+        let res = match receive_package(&mut rdr).await {
+            Ok((id, data)) => match MessageId::try_from(id) {
+                Ok(MessageId::Ping) => match proto::Ping::decode(data.as_slice()) {
+                    Ok(ping) => {
+                        let pong = proto::Pong { nonce: ping.nonce };
+                        match send_package(&mut *wrt.lock().await, MessageId::Pong, pong).await {
+                            Ok(()) => continue,
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Err(e) => Err(e.into()),
+                },
+                Ok(id) => Ok((id, data)),
+                Err(_) => {
+                    log::debug!("Skipping unknown message id {}", id);
+                    continue;
+                }
+            },
+            Err(e) => Err(e),
+        };
+
+        let failed = res.is_err();
+
+        if tx.send(res).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn connect_tls(
+    address: &str,
+    options: &TlsOptions,
+    tcp: TcpStream,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let mut config = ClientConfig::new();
+
+    for cert in load_certs(&options.ca)? {
+        config.root_store.add(&cert)?;
+    }
+
+    if let (Some(cert), Some(key)) = (&options.cert, &options.key) {
+        config.set_single_client_cert(load_certs(cert)?, load_key(key)?)?;
+    }
+
+    let host = match options.server_name {
+        Some(ref name) => name.as_str(),
+        None => address_host(address),
+    };
+
+    // Certificates for IP addresses are not supported by webpki
+    if host.parse::<IpAddr>().is_ok() {
+        anyhow::bail!(
+            "TLS can not verify the IP address {}, give the host name of the server certificate as server name",
+            host
+        );
+    }
+
+    let domain = DNSNameRef::try_from_ascii_str(host)
+        .map_err(|_| anyhow::format_err!("{} is not a valid TLS server name", host))?;
+
+    let connector = TlsConnector::from(Arc::new(config));
+
+    Ok(connector.connect(domain, tcp).await?)
+}
+
+/// The host of host:port, without the brackets of an IPv6 address
+fn address_host(address: &str) -> &str {
+    let host = address.rsplit_once(':').map(|(h, _)| h).unwrap_or(address);
+
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ip) => ip,
+        None => host,
+    }
+}
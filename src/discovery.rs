@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::protocol::{proto, PROTOCOL_VERSION};
+
+pub const SERVICE_TYPE: &str = "_nino._tcp.local.";
+pub const DEFAULT_PORT: u16 = 7583;
+
+/// A nino server found on the local network
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub name: String,
+    pub version: String,
+    pub protocol_version: u32,
+    /// Lowercase capability names, as advertised in the TXT record
+    pub capabilities: Vec<String>,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// The mDNS host name, without the trailing dot
+    pub hostname: String,
+}
+
+impl Instance {
+    /// host:port to connect to, IPv4 is preferred
+    pub fn address(&self) -> Option<String> {
+        let ip = self
+            .addresses
+            .iter()
+            .find(|ip| ip.is_ipv4())
+            .or_else(|| self.addresses.first())?;
+
+        Some(match ip {
+            IpAddr::V4(ip) => format!("{}:{}", ip, self.port),
+            IpAddr::V6(ip) => format!("[{}]:{}", ip, self.port),
+        })
+    }
+
+    fn from_info(info: &ServiceInfo) -> Instance {
+        let name = match info.get_property_val_str("name") {
+            Some(name) => name.into(),
+            None => info
+                .get_fullname()
+                .trim_end_matches(SERVICE_TYPE)
+                .trim_end_matches('.')
+                .into(),
+        };
+
+        let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        addresses.sort();
+
+        Instance {
+            name,
+            version: info.get_property_val_str("version").unwrap_or("").into(),
+            protocol_version: info
+                .get_property_val_str("protocol")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            capabilities: info
+                .get_property_val_str("capabilities")
+                .unwrap_or("")
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(Into::into)
+                .collect(),
+            addresses,
+            port: info.get_port(),
+            hostname: info.get_hostname().trim_end_matches('.').into(),
+        }
+    }
+}
+
+/// Advertise a server on the local network, it stays advertised until the daemon is dropped
+pub fn advertise(
+    name: &str,
+    version: &str,
+    port: u16,
+    capabilities: &[proto::Capability],
+) -> Result<ServiceDaemon> {
+    let capabilities: Vec<String> = capabilities
+        .iter()
+        .map(|c| format!("{:?}", c).to_lowercase())
+        .collect();
+
+    let mut properties = HashMap::new();
+    properties.insert("name".to_string(), name.to_string());
+    properties.insert("version".to_string(), version.to_string());
+    properties.insert("protocol".to_string(), PROTOCOL_VERSION.to_string());
+    properties.insert("capabilities".to_string(), capabilities.join(","));
+
+    // Addresses are filled in and kept up to date by the daemon
+    let info = ServiceInfo::new(SERVICE_TYPE, name, &host_name(name), (), port, properties)?
+        .enable_addr_auto();
+
+    let daemon = ServiceDaemon::new()?;
+    daemon.register(info)?;
+
+    Ok(daemon)
+}
+
+/// The mDNS host name for an instance name, anything but ASCII letters and digits becomes a dash
+fn host_name(name: &str) -> String {
+    let host: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect();
+
+    format!("nino-{}.local.", host)
+}
+
+/// List the servers that answer within the timeout, sorted by name
+pub fn browse(timeout: Duration) -> Result<Vec<Instance>> {
+    let mut found = BTreeMap::new();
+
+    search(timeout, |instance| {
+        found.insert(instance.name.clone(), instance);
+        false
+    })?;
+
+    Ok(found.into_values().collect())
+}
+
+/// Find a server by its instance name
+pub fn resolve(name: &str, timeout: Duration) -> Result<Instance> {
+    let mut found = None;
+
+    search(timeout, |instance| {
+        if instance.name == name {
+            found = Some(instance);
+        }
+        found.is_some()
+    })?;
+
+    found.ok_or_else(|| anyhow::format_err!("No nino instance named {} found", name))
+}
+
+/// Browse until `done` returns true or the timeout runs out
+fn search<F>(timeout: Duration, mut done: F) -> Result<()>
+where
+    F: FnMut(Instance) -> bool,
+{
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+
+    while let Ok(event) = events.recv_deadline(deadline) {
+        if let ServiceEvent::ServiceResolved(info) = event {
+            if done(Instance::from_info(&info)) {
+                break;
+            }
+        }
+    }
+
+    let _ = daemon.shutdown();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(addresses: &[&str]) -> Instance {
+        Instance {
+            name: "test".into(),
+            version: String::new(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            port: DEFAULT_PORT,
+            hostname: String::new(),
+        }
+    }
+
+    #[test]
+    fn ipv4_addresses_are_preferred() {
+        let both = instance(&["fe80::1", "192.168.1.2"]);
+        assert_eq!(both.address().as_deref(), Some("192.168.1.2:7583"));
+
+        let v6 = instance(&["fe80::1"]);
+        assert_eq!(v6.address().as_deref(), Some("[fe80::1]:7583"));
+
+        assert_eq!(instance(&[]).address(), None);
+    }
+
+    #[test]
+    fn host_names_are_slugged() {
+        assert_eq!(host_name("Living Room"), "nino-living-room.local.");
+        assert_eq!(host_name("pc2.lan"), "nino-pc2-lan.local.");
+    }
+
+    #[test]
+    fn advertised_properties_are_read_back() {
+        let mut properties = HashMap::new();
+        properties.insert("name".to_string(), "Living Room".to_string());
+        properties.insert("version".to_string(), "1.2.3".to_string());
+        properties.insert("protocol".to_string(), "7".to_string());
+        properties.insert("capabilities".to_string(), "deltas,heartbeat".to_string());
+
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "Living Room",
+            &host_name("Living Room"),
+            "192.168.1.2",
+            1234,
+            properties,
+        )
+        .unwrap();
+
+        let instance = Instance::from_info(&info);
+        assert_eq!(instance.name, "Living Room");
+        assert_eq!(instance.version, "1.2.3");
+        assert_eq!(instance.protocol_version, 7);
+        assert_eq!(instance.capabilities, ["deltas", "heartbeat"]);
+        assert_eq!(instance.address().as_deref(), Some("192.168.1.2:1234"));
+        assert_eq!(instance.hostname, "nino-living-room.local");
+    }
+
+    #[test]
+    fn the_service_name_is_used_without_a_name_property() {
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "Office",
+            &host_name("Office"),
+            "192.168.1.3",
+            DEFAULT_PORT,
+            HashMap::new(),
+        )
+        .unwrap();
+
+        let instance = Instance::from_info(&info);
+        assert_eq!(instance.name, "Office");
+        assert_eq!(instance.protocol_version, 0);
+        assert!(instance.capabilities.is_empty());
+    }
+}
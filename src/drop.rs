@@ -26,3 +26,19 @@ impl<T> Drop for DropJoin<T> {
         }
     }
 }
+
+/// Aborts a tokio task when dropped so it can not outlive its owner
+#[derive(Debug)]
+pub struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> AbortOnDrop<T> {
+    pub fn new(handle: tokio::task::JoinHandle<T>) -> AbortOnDrop<T> {
+        AbortOnDrop(handle)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
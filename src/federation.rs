@@ -0,0 +1,437 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::Result;
+use nino::client::{Client, Options};
+use nino::protocol::{proto, MessageId};
+use prost::Message;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    drop::AbortOnDrop,
+    net::Rejection,
+    sensor::{Sensor, SensorId, Sensors, REMOTE_START},
+    Config, Global,
+};
+
+/// How long a forwarded request waits for the peer to answer
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before connecting to a lost peer again
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Upstream nino instances whose sensors are republished by this one
+#[derive(Debug, Default)]
+pub struct Peers {
+    peers: Vec<Peer>,
+    /// The peer and the peers id of each remote sensor
+    routes: dashmap::DashMap<SensorId, (usize, u32)>,
+    locals: dashmap::DashMap<(usize, u32), SensorId>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    options: Options,
+    /// Instance name from the peers hello, None until it has connected
+    name: RwLock<Option<String>>,
+    requests: mpsc::Sender<Forward>,
+}
+
+/// A request from one of our clients for a peer
+#[derive(Debug)]
+pub struct Forward {
+    peer: usize,
+    request: Request,
+    /// The sensor to report back in the ack
+    sensor: Option<SensorId>,
+    reply: Option<oneshot::Sender<Result<(), Rejection>>>,
+}
+
+#[derive(Debug)]
+enum Request {
+    Pwm(proto::SetPwm),
+    Config(proto::SensorConfig),
+}
+
+/// The receiving end of each peers requests, handed to `start`
+pub type Requests = Vec<mpsc::Receiver<Forward>>;
+
+impl Peers {
+    pub fn new(options: &[Options]) -> (Peers, Requests) {
+        let mut requests = vec![];
+
+        let peers = options
+            .iter()
+            .map(|o| {
+                let (tx, rx) = mpsc::channel(16);
+                requests.push(rx);
+
+                Peer {
+                    options: o.clone(),
+                    name: RwLock::new(None),
+                    requests: tx,
+                }
+            })
+            .collect();
+
+        let peers = Peers {
+            peers,
+            ..Default::default()
+        };
+
+        (peers, requests)
+    }
+
+    /// Requests that belong to a peer, None if the request is for this server
+    pub fn route(&self, id: MessageId, data: &[u8]) -> Option<Result<Forward, Rejection>> {
+        use proto::error::Code;
+
+        match id {
+            MessageId::SensorConfig => {
+                let cfg = match proto::SensorConfig::decode(data) {
+                    Ok(cfg) => cfg,
+                    Err(e) => return Some(Err(e.into())),
+                };
+
+                let sensor = SensorId::from_usize(cfg.id as usize);
+
+                if !sensor.is_remote() {
+                    return None;
+                }
+
+                Some(match self.routes.get(&sensor) {
+                    Some(route) => {
+                        let (peer, remote) = *route;
+
+                        Ok(Forward {
+                            peer,
+                            request: Request::Config(proto::SensorConfig { id: remote, ..cfg }),
+                            sensor: Some(sensor),
+                            reply: None,
+                        })
+                    }
+                    None => Err(Rejection(Code::NotFound, format!("No sensor {:?}", sensor))),
+                })
+            }
+            MessageId::Pwm => {
+                let pwm = match proto::SetPwm::decode(data) {
+                    Ok(pwm) => pwm,
+                    Err(e) => return Some(Err(e.into())),
+                };
+
+                if pwm.instance.is_empty() || pwm.instance == Config::global().name {
+                    return None;
+                }
+
+                let peer = self.peers.iter().position(|p| {
+                    p.name.read().expect("Read peer name").as_ref() == Some(&pwm.instance)
+                });
+
+                Some(match peer {
+                    Some(peer) => Ok(Forward {
+                        peer,
+                        request: Request::Pwm(proto::SetPwm {
+                            instance: String::new(),
+                            ..pwm
+                        }),
+                        sensor: None,
+                        reply: None,
+                    }),
+                    None => Err(Rejection(
+                        Code::NotFound,
+                        format!("No peer instance {}", pwm.instance),
+                    )),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Send a request to its peer and wait for the peer to answer it
+    pub async fn forward(&self, mut forward: Forward) -> Result<Option<SensorId>, Rejection> {
+        use proto::error::Code;
+
+        let (tx, rx) = oneshot::channel();
+        forward.reply = Some(tx);
+
+        let sensor = forward.sensor;
+
+        if let Err(e) = self.peers[forward.peer].requests.try_send(forward) {
+            return Err(Rejection(Code::Unavailable, format!("{}", e)));
+        }
+
+        match tokio::time::timeout(FORWARD_TIMEOUT, rx).await {
+            Ok(Ok(Ok(()))) => Ok(sensor),
+            Ok(Ok(Err(rejection))) => Err(rejection),
+            Ok(Err(_)) => Err(Rejection(Code::Unavailable, "Peer disconnected".into())),
+            Err(_) => Err(Rejection(Code::Unavailable, "Peer did not answer".into())),
+        }
+    }
+
+    /// The local id of a peers sensor, a sensor keeps its id across restarts
+    fn local_id(&self, peer: usize, instance: &str, remote: u32) -> Result<SensorId> {
+        if let Some(id) = self.locals.get(&(peer, remote)) {
+            return Ok(*id);
+        }
+
+        let db = sled::Db::global();
+        let tree = db.open_tree("federation-ids")?;
+        let key = format!("{}/{}", instance, remote);
+
+        let id = match tree.get(&key)? {
+            Some(id) => usize::from_be_bytes(id.as_ref().try_into()?),
+            None => {
+                let id = REMOTE_START + db.generate_id()? as usize;
+                tree.insert(&key, &id.to_be_bytes())?;
+                id
+            }
+        };
+
+        let id = SensorId::from_usize(id);
+
+        self.routes.insert(id, (peer, remote));
+        self.locals.insert((peer, remote), id);
+
+        Ok(id)
+    }
+
+    fn insert(&self, peer: usize, instance: &str, sensor: proto::sensors::Sensor) -> Result<()> {
+        // Sensors the peer federates itself are left to their own instance, that way
+        // two instances federating each other do not loop
+        if !sensor.instance.is_empty() {
+            return Ok(());
+        }
+
+        let id = self.local_id(peer, instance, sensor.id)?;
+        let retention = Config::global().retention;
+
+        let remote = Sensor {
+            alias: sensor.alias,
+            values: sensor
+                .values
+                .into_iter()
+                .take(retention)
+                .collect::<VecDeque<_>>(),
+            unit: sensor.unit,
+            rate: sensor.rate as usize,
+            source: sensor
+                .optional_source
+                .map(|proto::sensors::sensor::OptionalSource::Source(s)| s),
+            error: sensor
+                .optional_error
+                .map(|proto::sensors::sensor::OptionalError::Error(e)| e),
+            instance: Some(instance.into()),
+            trigger: proto::Trigger::from_i32(sensor.trigger)
+                .map(Into::into)
+                .unwrap_or_default(),
+        };
+
+        Sensors::global().insert_remote(&id, remote);
+
+        Ok(())
+    }
+
+    /// Replace all sensors of a peer
+    fn sync(
+        &self,
+        peer: usize,
+        instance: &str,
+        sensors: Vec<proto::sensors::Sensor>,
+    ) -> Result<()> {
+        let present: HashSet<u32> = sensors.iter().map(|s| s.id).collect();
+
+        for sensor in sensors {
+            self.insert(peer, instance, sensor)?;
+        }
+
+        let gone: Vec<u32> = self
+            .locals
+            .iter()
+            .filter(|l| l.key().0 == peer && !present.contains(&l.key().1))
+            .map(|l| l.key().1)
+            .collect();
+
+        for remote in gone {
+            self.remove(peer, remote);
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, peer: usize, remote: u32) {
+        if let Some((_, id)) = self.locals.remove(&(peer, remote)) {
+            self.routes.remove(&id);
+            Sensors::global().remove_remote(&id);
+        }
+    }
+
+    fn local(&self, peer: usize, remote: u32) -> Option<SensorId> {
+        self.locals.get(&(peer, remote)).map(|id| *id)
+    }
+
+    /// The sensors of a lost peer stay listed but in error until it is back
+    fn disconnected(&self, peer: usize) {
+        let sensors = Sensors::global();
+
+        for local in self.locals.iter().filter(|l| l.key().0 == peer) {
+            sensors.set_error(local.value(), "Peer disconnected".into());
+        }
+    }
+}
+
+/// Connect to every peer, each peer is reconnected until the tasks are dropped
+pub fn start(requests: Requests) -> Vec<AbortOnDrop<()>> {
+    requests
+        .into_iter()
+        .enumerate()
+        .map(|(peer, requests)| AbortOnDrop::new(tokio::spawn(run_peer(peer, requests))))
+        .collect()
+}
+
+async fn run_peer(peer: usize, mut requests: mpsc::Receiver<Forward>) {
+    let peers = Peers::global();
+    let address = &peers.peers[peer].options.address;
+
+    loop {
+        if let Err(e) = session(peer, &mut requests).await {
+            log::warn!("Peer {} disconnected {:?}", address, e);
+        }
+
+        peers.disconnected(peer);
+
+        // Requests made while the peer is away fail right away
+        let retry = tokio::time::sleep(RECONNECT_DELAY);
+        tokio::pin!(retry);
+
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                Some(forward) = requests.recv() => {
+                    if let Some(reply) = forward.reply {
+                        let message = format!("Peer {} is not connected", address);
+                        let _ = reply.send(Err(Rejection(proto::error::Code::Unavailable, message)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn session(peer: usize, requests: &mut mpsc::Receiver<Forward>) -> Result<()> {
+    let peers = Peers::global();
+    let sensors = Sensors::global();
+
+    // A peer that accepts but never finishes the handshake would hold this forever
+    let options = &peers.peers[peer].options;
+    let handshake = tokio::time::timeout(Config::global().idle_timeout, Client::connect(options));
+    let mut client = handshake
+        .await
+        .map_err(|_| anyhow::format_err!("{} did not finish the handshake", options.address))??;
+    let instance = client.hello.name.clone();
+
+    if instance == Config::global().name {
+        anyhow::bail!("Peer has the same instance name as this server");
+    }
+
+    log::info!(
+        "Federating {} from {}",
+        instance,
+        peers.peers[peer].options.address
+    );
+
+    *peers.peers[peer].name.write().expect("Write peer name") = Some(instance.clone());
+    peers.sync(peer, &instance, std::mem::take(&mut client.sensors))?;
+
+    let acks = client.supports(proto::Capability::Acks);
+    let mut pending: HashMap<u32, oneshot::Sender<Result<(), Rejection>>> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            res = client.receive() => {
+                let (id, data) = res?;
+
+                match id {
+                    MessageId::Value => {
+                        let value = proto::Value::decode(data.as_slice())?;
+
+                        if let Some(local) = peers.local(peer, value.id) {
+                            sensors.set(&local, value.value);
+                        }
+                    }
+                    MessageId::Sensors => {
+                        let list = proto::Sensors::decode(data.as_slice())?;
+                        peers.sync(peer, &instance, list.sensors)?;
+                    }
+                    MessageId::SensorChanged => {
+                        if let Some(sensor) = proto::SensorChanged::decode(data.as_slice())?.sensor {
+                            peers.insert(peer, &instance, sensor)?;
+                        }
+                    }
+                    MessageId::SensorError => {
+                        let error = proto::SensorError::decode(data.as_slice())?;
+
+                        if let Some(local) = peers.local(peer, error.id) {
+                            match error.optional_error {
+                                Some(proto::sensor_error::OptionalError::Error(e)) => {
+                                    sensors.set_error(&local, e)
+                                }
+                                None => sensors.clear_error(&local),
+                            }
+                        }
+                    }
+                    MessageId::SensorRemoved => {
+                        let removed = proto::SensorRemoved::decode(data.as_slice())?;
+                        peers.remove(peer, removed.id);
+                    }
+                    MessageId::Ack => {
+                        let ack = proto::Ack::decode(data.as_slice())?;
+
+                        if let Some(reply) = pending.remove(&ack.request_id) {
+                            let _ = reply.send(Ok(()));
+                        }
+                    }
+                    MessageId::Error => {
+                        let error = proto::Error::decode(data.as_slice())?;
+                        let code = proto::error::Code::from_i32(error.code)
+                            .unwrap_or(proto::error::Code::Unknown);
+
+                        if let Some(reply) = pending.remove(&error.request_id) {
+                            let message = format!("{}: {}", instance, error.message);
+                            let _ = reply.send(Err(Rejection(code, message)));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Some(forward) = requests.recv() => {
+                let request_id = client.request_id();
+
+                match forward.request {
+                    Request::Pwm(pwm) => {
+                        let pwm = proto::SetPwm { request_id, ..pwm };
+                        client.send(MessageId::Pwm, pwm).await?;
+                    }
+                    Request::Config(cfg) => {
+                        let cfg = proto::SensorConfig { request_id, ..cfg };
+                        client.send(MessageId::SensorConfig, cfg).await?;
+                    }
+                }
+
+                if let Some(reply) = forward.reply {
+                    // Without acks there is nothing more to wait for
+                    match acks {
+                        true => {
+                            // Requests that timed out are no longer waited for
+                            pending.retain(|_, reply| !reply.is_closed());
+                            pending.insert(request_id, reply);
+                        }
+                        false => {
+                            let _ = reply.send(Ok(()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
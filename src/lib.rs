@@ -0,0 +1,4 @@
+pub mod client;
+pub mod discovery;
+pub mod pem;
+pub mod protocol;
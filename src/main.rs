@@ -1,13 +1,18 @@
+mod auth;
 mod drop;
+mod federation;
+mod mqtt;
 mod net;
 mod pwm;
 mod sensor;
+mod tls;
 
-use std::{convert::TryInto, sync::Arc};
+use std::{convert::TryInto, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use clap::{App, Arg};
 use drop::DropJoin;
+use nino::{discovery, protocol::proto};
 use once_cell::sync::OnceCell;
 use tokio::net::TcpListener;
 
@@ -35,6 +40,9 @@ global!(Sensors, SENSORS);
 global!(Config, CONFIG);
 global!(sled::Db, DB);
 global!(Workers, WORKERS);
+global!(net::Clients, CLIENTS);
+global!(federation::Peers, PEERS);
+global!(sensor::library::Library, LIBRARY);
 
 fn main() -> Result<()> {
     env_logger::init();
@@ -56,15 +64,316 @@ fn main() -> Result<()> {
                 .about("The number of sensor values the server will store for each sensor")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("broadcast-buffer")
+                .long("broadcast-buffer")
+                .about("Updates queued for each client before a slow client has to resync, defaults to 64")
+                .validator(at_least_one)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("subscriber-buffer")
+                .long("subscriber-buffer")
+                .about("Updates queued for each internal sensor subscriber before they are dropped, defaults to 25")
+                .validator(at_least_one)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("heartbeat-interval")
+                .long("heartbeat-interval")
+                .about("Seconds between pings to clients that support heartbeats, defaults to 15. Must be shorter than the idle timeout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .about("Seconds a client with heartbeats may stay quiet before it is disconnected, defaults to 45. Older clients are only held to it until they are ready")
+                .validator(at_least_one)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mqtt")
+                .long("mqtt")
+                .about("Publish sensors and fans to this MQTT broker, host[:port]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mqtt-user")
+                .long("mqtt-user")
+                .about("Username for the MQTT broker")
+                .requires("mqtt")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mqtt-password")
+                .long("mqtt-password")
+                .about("Password for the MQTT broker")
+                .requires("mqtt-user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("discovery-prefix")
+                .long("discovery-prefix")
+                .about("Home Assistant MQTT discovery prefix, defaults to homeassistant")
+                .requires("mqtt")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("secret-file")
+                .long("secret-file")
+                .about("File with the shared secret clients sign the hello nonce with")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("add-token")
+                .long("add-token")
+                .about("Store a client token, ROLE:TOKEN where ROLE is read, operator or admin")
+                .multiple_occurrences(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("add-cert")
+                .long("add-cert")
+                .about("Grant a TLS client certificate a role, ROLE:SHA256 fingerprint")
+                .multiple_occurrences(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .about("PEM certificate chain, enables TLS for client connections")
+                .requires("tls-key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .about("PEM private key for the TLS certificate")
+                .requires("tls-cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("tls-client-ca")
+                .long("tls-client-ca")
+                .about("PEM CA certificates used to verify client certificates")
+                .requires("tls-cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("peer")
+                .long("peer")
+                .about("Federate the sensors of another Nino server, host:port")
+                .multiple_occurrences(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("peer-secret-file")
+                .long("peer-secret-file")
+                .about("File with the shared secret of the peers")
+                .requires("peer")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("peer-token")
+                .long("peer-token")
+                .about("Token to authenticate with at the peers")
+                .requires("peer")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("peer-tls-ca")
+                .long("peer-tls-ca")
+                .about("Connect to the peers with TLS, verified against these PEM CA certificates")
+                .requires("peer")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("no-mdns")
+                .long("no-mdns")
+                .about("Do not advertise the server on the local network with mDNS"),
+        )
+        .arg(
+            Arg::new("persist-script-state")
+                .long("persist-script-state")
+                .about("Save the variables of virtual sensor scripts, so they survive a restart"),
+        )
+        .arg(
+            Arg::new("script-max-operations")
+                .long("script-max-operations")
+                .about("Operations a virtual sensor script may run per evaluation, defaults to 1000000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("script-max-call-levels")
+                .long("script-max-call-levels")
+                .about("How deep virtual sensor scripts may nest function calls, defaults to 32")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("script-max-string-size")
+                .long("script-max-string-size")
+                .about("Longest string a virtual sensor script may build, defaults to 65536")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("script-max-array-size")
+                .long("script-max-array-size")
+                .about("Largest array a virtual sensor script may build, defaults to 10000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("script-timeout")
+                .long("script-timeout")
+                .about("Milliseconds a virtual sensor script may run per evaluation, defaults to 1000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("script-threads")
+                .long("script-threads")
+                .about("Threads evaluating virtual sensor scripts, defaults to 2")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("revoke-token")
+                .long("revoke-token")
+                .about("Remove a stored client token")
+                .multiple_occurrences(true)
+                .takes_value(true),
+        )
         .get_matches();
 
+    let secret = match matches.value_of("secret-file") {
+        Some(path) => Some(std::fs::read_to_string(path)?.trim().as_bytes().to_vec()),
+        None => None,
+    };
+
+    let peer_secret = match matches.value_of("peer-secret-file") {
+        Some(path) => Some(std::fs::read_to_string(path)?.trim().as_bytes().to_vec()),
+        None => None,
+    };
+
+    let peers = matches
+        .values_of("peer")
+        .into_iter()
+        .flatten()
+        .map(|address| nino::client::Options {
+            address: address.into(),
+            secret: peer_secret.clone(),
+            token: matches.value_of("peer-token").map(Into::into),
+            tls: matches
+                .value_of("peer-tls-ca")
+                .map(|ca| nino::client::TlsOptions {
+                    ca: ca.into(),
+                    ..Default::default()
+                }),
+            capabilities: vec![
+                proto::Capability::Acks,
+                proto::Capability::Deltas,
+                proto::Capability::Heartbeat,
+            ],
+        })
+        .collect();
+
     SENSORS.set(Sensors::new()).unwrap();
     CONFIG.set(Config {
         name: matches.value_of("name").unwrap().into(),
         retention: matches.value_of_t("retention").unwrap_or(100),
+        broadcast_buffer: matches.value_of_t("broadcast-buffer").unwrap_or(64),
+        subscriber_buffer: matches.value_of_t("subscriber-buffer").unwrap_or(25),
+        // Intervals can not be zero
+        heartbeat_interval: Duration::from_secs(
+            matches.value_of_t("heartbeat-interval").unwrap_or(15).max(1),
+        ),
+        idle_timeout: Duration::from_secs(matches.value_of_t("idle-timeout").unwrap_or(45)),
+        mqtt: matches.value_of("mqtt").map(|broker| {
+            let (host, port) = match broker.rsplit_once(':') {
+                Some((host, port)) => (host.into(), port.parse().unwrap_or(1883)),
+                None => (broker.into(), 1883),
+            };
+
+            MqttConfig {
+                host,
+                port,
+                user: matches.value_of("mqtt-user").map(Into::into),
+                password: matches.value_of("mqtt-password").map(Into::into),
+                discovery_prefix: matches
+                    .value_of("discovery-prefix")
+                    .unwrap_or("homeassistant")
+                    .into(),
+            }
+        }),
+        secret,
+        tls: matches.value_of("tls-cert").map(|cert| TlsConfig {
+            cert: cert.into(),
+            key: matches.value_of("tls-key").unwrap().into(),
+            client_ca: matches.value_of("tls-client-ca").map(Into::into),
+        }),
+        peers,
+        persist_script_state: matches.is_present("persist-script-state"),
+        // Zero would mean no limit to Rhai
+        script_limits: ScriptLimits {
+            max_operations: matches
+                .value_of_t("script-max-operations")
+                .unwrap_or(1_000_000)
+                .max(1),
+            max_call_levels: matches
+                .value_of_t("script-max-call-levels")
+                .unwrap_or(32)
+                .max(1),
+            max_string_size: matches
+                .value_of_t("script-max-string-size")
+                .unwrap_or(65536)
+                .max(1),
+            max_array_size: matches
+                .value_of_t("script-max-array-size")
+                .unwrap_or(10000)
+                .max(1),
+            timeout: Duration::from_millis(
+                matches.value_of_t("script-timeout").unwrap_or(1000).max(1),
+            ),
+        },
+        script_threads: matches.value_of_t("script-threads").unwrap_or(2).max(1),
     }).unwrap();
+
+    // The first ping would go out after the client was already dropped as idle
+    let cfg = Config::global();
+    if cfg.heartbeat_interval >= cfg.idle_timeout {
+        anyhow::bail!(
+            "The heartbeat interval of {}s must be shorter than the idle timeout of {}s",
+            cfg.heartbeat_interval.as_secs(),
+            cfg.idle_timeout.as_secs()
+        );
+    }
+
     DB.set(sled::open("./settings.db")?).unwrap();
     WORKERS.set(Default::default()).unwrap();
+    LIBRARY.set(Default::default()).unwrap();
+    CLIENTS.set(Default::default()).unwrap();
+
+    let (peers, peer_requests) = federation::Peers::new(&Config::global().peers);
+    PEERS.set(peers).unwrap();
+
+    for token in matches.values_of("add-token").into_iter().flatten() {
+        let (access, token) = token
+            .split_once(':')
+            .ok_or_else(|| anyhow::format_err!("Token must be given as ROLE:TOKEN"))?;
+
+        auth::add_token(token, access.parse()?)?;
+    }
+
+    for cert in matches.values_of("add-cert").into_iter().flatten() {
+        let (access, fingerprint) = cert
+            .split_once(':')
+            .ok_or_else(|| anyhow::format_err!("Certificate must be given as ROLE:SHA256"))?;
+
+        auth::add_certificate(fingerprint, access.parse()?)?;
+    }
+
+    for token in matches.values_of("revoke-token").into_iter().flatten() {
+        if !auth::revoke_token(token)? {
+            log::warn!("Token to revoke was not stored");
+        }
+    }
 
     let workers = Workers::global();
 
@@ -88,9 +397,11 @@ fn main() -> Result<()> {
 
         wrk.push((vec![SensorId::RPi], poll_rpi_tmp()?));
         wrk.push((vec![SensorId::RPM0, SensorId::RPM1], poll_rpm()?));
+        wrk.push((vec![], sensor::watch_external()?));
+        wrk.push((vec![], sensor::run_virtual_sensors()?));
     }
 
-    let (tx, _rx) = tokio::sync::broadcast::channel(5);
+    let (tx, _rx) = tokio::sync::broadcast::channel(Config::global().broadcast_buffer);
     let broadcaster = Arc::new(tx);
     let _broadcast_handle = broadcast_sensors(broadcaster.clone())?;
 
@@ -98,26 +409,68 @@ fn main() -> Result<()> {
 
     let _pwm_handle = listen_pwm(pwm_rx)?;
 
+    let _mqtt_handles = match Config::global().mqtt {
+        Some(ref cfg) => mqtt::start(cfg, pwm_tx.clone())?,
+        None => vec![],
+    };
+
+    let acceptor = match Config::global().tls {
+        Some(ref cfg) => Some(tls::acceptor(cfg)?),
+        None => None,
+    };
+
     let rt = tokio::runtime::Runtime::new()?;
     let _ok: Result<()> = rt.block_on(async {
-        let listener = TcpListener::bind("0.0.0.0:7583").await?;
+        let listener = TcpListener::bind(("0.0.0.0", discovery::DEFAULT_PORT)).await?;
+
+        let _peers = federation::start(peer_requests);
+
+        // A server that can not be discovered is still usable, so just log it
+        let _mdns = match matches.is_present("no-mdns") {
+            true => None,
+            false => discovery::advertise(
+                &Config::global().name,
+                VERSION,
+                discovery::DEFAULT_PORT,
+                net::CAPABILITIES,
+            )
+            .map_err(|e| log::warn!("Could not advertise with mDNS {:?}", e))
+            .ok(),
+        };
 
         loop {
             // The second item contains the IP and port of the new connection.
             let (socket, addr) = listener.accept().await?;
 
-            log::debug!("{:?} connected", addr);
+            log::info!("{} connected", addr);
 
             let listen = broadcaster.clone();
             let pwm = pwm_tx.clone();
+            let acceptor = acceptor.clone();
 
             tokio::spawn(async move {
-                match net::handle(socket, listen, pwm).await {
-                    Ok(_) => return,
-                    Err(e) => {
-                        log::error!("Socket error:\n{:?}", e);
-                        return;
+                // A client that never finishes the handshake is as idle as one that never
+                // sends ready
+                let timeout = Config::global().idle_timeout;
+
+                let res = match acceptor {
+                    Some(acceptor) => {
+                        let accept = tokio::time::timeout(timeout, acceptor.accept(socket));
+
+                        match accept.await {
+                            Ok(Ok(stream)) => match tls::peer_access(&stream) {
+                                Ok(access) => net::handle(stream, addr, access, listen, pwm).await,
+                                Err(e) => Err(e),
+                            },
+                            Ok(Err(e)) => Err(e.into()),
+                            Err(_) => Err(anyhow::format_err!("{} did not finish the TLS handshake", addr)),
+                        }
                     }
+                    None => net::handle(socket, addr, None, listen, pwm).await,
+                };
+
+                if let Err(e) = res {
+                    log::error!("Socket error:\n{:?}", e);
                 }
             });
         }
@@ -126,6 +479,16 @@ fn main() -> Result<()> {
     _ok
 }
 
+/// Buffers of 0 are refused by broadcast channels and drop every try_send otherwise,
+/// an idle timeout of 0 would disconnect every client right away
+fn at_least_one(value: &str) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".into()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
 fn broadcast_sensors(
     broadcast: Arc<tokio::sync::broadcast::Sender<SensorMessage>>,
 ) -> Result<DropJoin<()>> {
@@ -147,30 +510,44 @@ fn broadcast_sensors(
     Ok(DropJoin::new(handle))
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum PwmChannel {
     Pwm0,
     Pwm1,
 }
 
-fn listen_pwm(recv: crossbeam_channel::Receiver<(PwmChannel, f32)>) -> Result<DropJoin<()>> {
-    let handle = std::thread::Builder::new().name("pwm".into()).spawn(move || {
+impl PwmChannel {
+    pub fn key(self) -> &'static str {
+        match self {
+            PwmChannel::Pwm0 => "pwm0",
+            PwmChannel::Pwm1 => "pwm1",
+        }
+    }
+
+    /// The last duty cycle set on the channel or the default if it was never set
+    pub fn saved_duty(self) -> f32 {
         let database = sled::Db::global();
 
-        let def0 = database.get("pwm0").ok().flatten().and_then(|v| {
-            let value: &[u8] = &v;
-            let value = f32::from_be_bytes(value.try_into().ok()?);
-            Some(value)
-        }).unwrap_or(0.6);
+        let default = match self {
+            PwmChannel::Pwm0 => 0.6,
+            PwmChannel::Pwm1 => 0.28,
+        };
 
-        let def1 = database.get("pwm1").ok().flatten().and_then(|v| {
+        database.get(self.key()).ok().flatten().and_then(|v| {
             let value: &[u8] = &v;
             let value = f32::from_be_bytes(value.try_into().ok()?);
             Some(value)
-        }).unwrap_or(0.28);
+        }).unwrap_or(default)
+    }
+}
+
+fn listen_pwm(recv: crossbeam_channel::Receiver<(PwmChannel, f32)>) -> Result<DropJoin<()>> {
+    let handle = std::thread::Builder::new().name("pwm".into()).spawn(move || {
+        let database = sled::Db::global();
 
         let pwm = Pwm::new()?;
-        pwm.set_channel0(def0)?;
-        pwm.set_channel1(def1)?;
+        pwm.set_channel0(PwmChannel::Pwm0.saved_duty())?;
+        pwm.set_channel1(PwmChannel::Pwm1.saved_duty())?;
 
         for (chan, value) in recv.iter() {
             let v = value.to_be_bytes();
@@ -178,11 +555,11 @@ fn listen_pwm(recv: crossbeam_channel::Receiver<(PwmChannel, f32)>) -> Result<Dr
             match chan {
                 PwmChannel::Pwm0 => {
                     pwm.set_channel0(value.clamp(0.0, 1.0))?;
-                    database.insert("pwm0".as_bytes(), &v)?;
+                    database.insert(chan.key(), &v)?;
                 },
                 PwmChannel::Pwm1 => {
                     pwm.set_channel1(value.clamp(0.0, 1.0))?;
-                    database.insert("pwm1".as_bytes(), &v)?;
+                    database.insert(chan.key(), &v)?;
                 },
             }
 
@@ -210,4 +587,41 @@ impl std::ops::Deref for Workers {
 pub struct Config {
     pub name: String,
     pub retention: usize,
+    pub broadcast_buffer: usize,
+    pub subscriber_buffer: usize,
+    pub heartbeat_interval: Duration,
+    pub idle_timeout: Duration,
+    pub mqtt: Option<MqttConfig>,
+    pub secret: Option<Vec<u8>>,
+    pub tls: Option<TlsConfig>,
+    pub peers: Vec<nino::client::Options>,
+    pub persist_script_state: bool,
+    pub script_limits: ScriptLimits,
+    pub script_threads: usize,
+}
+
+/// What a virtual sensor script may use in one evaluation
+#[derive(Default, Debug, Clone)]
+pub struct ScriptLimits {
+    pub max_operations: u64,
+    pub max_call_levels: usize,
+    pub max_string_size: usize,
+    pub max_array_size: usize,
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub discovery_prefix: String,
 }
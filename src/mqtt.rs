@@ -0,0 +1,374 @@
+use std::{thread, time::Duration};
+
+use anyhow::Result;
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+
+use crate::{
+    drop::DropJoin,
+    sensor::{SensorId, SensorMessage, Sensors},
+    Config, Global, MqttConfig, PwmChannel, VERSION,
+};
+
+const CHANNELS: [PwmChannel; 2] = [PwmChannel::Pwm0, PwmChannel::Pwm1];
+
+/// Connect to the broker and keep Home Assistant in sync with our sensors and fans
+pub fn start(
+    cfg: &MqttConfig,
+    pwm: crossbeam_channel::Sender<(PwmChannel, f32)>,
+) -> Result<Vec<DropJoin<()>>> {
+    let mut options = MqttOptions::new(object_id("server"), &cfg.host, cfg.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        availability_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    if let Some(ref user) = cfg.user {
+        options.set_credentials(user, cfg.password.clone().unwrap_or_default());
+    }
+
+    let (client, mut connection) = Client::new(options, 25);
+
+    // Announcing fills the request channel, it can only drain while the connection
+    // thread keeps polling. One pending announce covers any number of reconnects
+    let (announce_tx, announce_rx) = crossbeam_channel::bounded(1);
+
+    let connection_handle = thread::Builder::new()
+        .name("mqtt".into())
+        .spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to MQTT broker");
+
+                        // Retained discovery can have been lost if the broker restarted,
+                        // so announce everything on each (re)connect
+                        let _ = announce_tx.try_send(());
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        let payload = String::from_utf8_lossy(&p.payload);
+                        handle_command(&p.topic, payload.trim(), &pwm);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("MQTT connection error {:?}", e);
+                        // The event loop reconnects on the next poll, dont spin on a dead broker
+                        thread::sleep(Duration::from_secs(5));
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+    let mut announce_client = client.clone();
+    let announce_handle = thread::Builder::new()
+        .name("mqtt-announce".into())
+        .spawn(move || {
+            for () in announce_rx {
+                if let Err(e) = announce(&mut announce_client) {
+                    log::error!("MQTT announce failed {:?}", e);
+                }
+            }
+
+            Ok(())
+        })?;
+
+    let mut sensor_client = client.clone();
+    let sensor_handle = thread::Builder::new()
+        .name("mqtt-sensors".into())
+        .spawn(move || {
+            let sensors = Sensors::global();
+
+            for message in sensors.subscribe() {
+                let res = match message {
+                    SensorMessage::Update(id, value) => sensor_client.publish(
+                        sensor_topic(id),
+                        QoS::AtMostOnce,
+                        false,
+                        format!("{}", value),
+                    ),
+                    // Aliases and units show up in Home Assistant through the discovery config
+                    SensorMessage::Config(id) => publish_sensor(&mut sensor_client, id),
+                    SensorMessage::Remove(id) => sensor_client.publish(
+                        discovery_topic("sensor", &id.to_usize().to_string()),
+                        QoS::AtLeastOnce,
+                        true,
+                        vec![],
+                    ),
+                    _ => Ok(()),
+                };
+
+                if let Err(e) = res {
+                    log::error!("MQTT publish failed {:?}", e);
+                }
+            }
+
+            Ok(())
+        })?;
+
+    let mut fan_client = client;
+    let fan_handle = thread::Builder::new()
+        .name("mqtt-fans".into())
+        .spawn(move || {
+            let database = sled::Db::global();
+
+            // The pwm thread stores every new duty cycle so this sees changes from all clients
+            for _event in database.watch_prefix("pwm") {
+                for chan in CHANNELS.iter() {
+                    if let Err(e) = publish_fan_state(&mut fan_client, *chan) {
+                        log::error!("MQTT publish failed {:?}", e);
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+    Ok(vec![
+        DropJoin::new(connection_handle),
+        DropJoin::new(announce_handle),
+        DropJoin::new(sensor_handle),
+        DropJoin::new(fan_handle),
+    ])
+}
+
+fn announce(client: &mut Client) -> Result<()> {
+    client.publish(availability_topic(), QoS::AtLeastOnce, true, "online")?;
+
+    let ids: Vec<SensorId> = Sensors::global().iter().map(|s| *s.key()).collect();
+    for id in ids {
+        publish_sensor(client, id)?;
+    }
+
+    for chan in CHANNELS.iter() {
+        let cfg = Config::global();
+        let nr = channel_nr(*chan);
+        let fan = format!("{}/fan/{}", base_topic(), nr);
+
+        let discovery = json!({
+            "name": format!("{} Pwm{}", cfg.name, nr),
+            "unique_id": object_id(chan.key()),
+            "command_topic": format!("{}/set", fan),
+            "state_topic": format!("{}/state", fan),
+            "percentage_command_topic": format!("{}/percentage/set", fan),
+            "percentage_state_topic": format!("{}/percentage", fan),
+            "availability_topic": availability_topic(),
+            "device": device(),
+        });
+
+        client.publish(
+            discovery_topic("fan", chan.key()),
+            QoS::AtLeastOnce,
+            true,
+            discovery.to_string(),
+        )?;
+
+        client.subscribe(format!("{}/set", fan), QoS::AtLeastOnce)?;
+        client.subscribe(format!("{}/percentage/set", fan), QoS::AtLeastOnce)?;
+
+        publish_fan_state(client, *chan)?;
+    }
+
+    Ok(())
+}
+
+fn publish_sensor(client: &mut Client, id: SensorId) -> Result<(), rumqttc::ClientError> {
+    let cfg = Config::global();
+
+    let mut discovery = match Sensors::global().get(&id) {
+        Some(sensor) => json!({
+            "name": format!("{} {}", cfg.name, sensor.alias),
+            "unique_id": object_id(&id.to_usize().to_string()),
+            "state_topic": sensor_topic(id),
+            "state_class": "measurement",
+            "unit_of_measurement": sensor.unit,
+            "availability_topic": availability_topic(),
+            "device": device(),
+        }),
+        None => return Ok(()),
+    };
+
+    match discovery["unit_of_measurement"].as_str() {
+        Some("°C") => discovery["device_class"] = "temperature".into(),
+        Some("RPM") => discovery["icon"] = "mdi:fan".into(),
+        _ => {}
+    }
+
+    client.publish(
+        discovery_topic("sensor", &id.to_usize().to_string()),
+        QoS::AtLeastOnce,
+        true,
+        discovery.to_string(),
+    )
+}
+
+fn publish_fan_state(client: &mut Client, chan: PwmChannel) -> Result<(), rumqttc::ClientError> {
+    let fan = format!("{}/fan/{}", base_topic(), channel_nr(chan));
+    let duty = chan.saved_duty().clamp(0.0, 1.0);
+
+    let state = if duty > 0.0 { "ON" } else { "OFF" };
+    client.publish(format!("{}/state", fan), QoS::AtLeastOnce, true, state)?;
+
+    let percentage = format!("{}", (duty * 100.0).round() as u32);
+    client.publish(format!("{}/percentage", fan), QoS::AtLeastOnce, true, percentage)
+}
+
+fn handle_command(topic: &str, payload: &str, pwm: &crossbeam_channel::Sender<(PwmChannel, f32)>) {
+    let base = format!("{}/fan/", base_topic());
+
+    let (chan, command) = match fan_command(topic, &base) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+
+    if let Some(value) = fan_duty(command, payload, || chan.saved_duty()) {
+        if let Err(e) = pwm.try_send((chan, value)) {
+            log::error!("Could not send to PWM\n{:?}", e);
+        }
+    }
+}
+
+/// The channel and command of a fan command topic under `base`
+fn fan_command<'a>(topic: &'a str, base: &str) -> Option<(PwmChannel, &'a str)> {
+    let (nr, command) = topic.strip_prefix(base)?.split_once('/')?;
+
+    let chan = match nr {
+        "0" => PwmChannel::Pwm0,
+        "1" => PwmChannel::Pwm1,
+        _ => return None,
+    };
+
+    Some((chan, command))
+}
+
+/// The duty cycle a command sets, none if it leaves the fan as it is
+fn fan_duty<F>(command: &str, payload: &str, saved_duty: F) -> Option<f32>
+where
+    F: FnOnce() -> f32,
+{
+    match (command, payload) {
+        ("set", "OFF") => Some(0.0),
+        // Turning on a fan that is off runs it at full speed, otherwise keep the speed
+        ("set", "ON") if saved_duty() <= 0.0 => Some(1.0),
+        ("set", "ON") => None,
+        ("percentage/set", p) => match p.parse::<f32>() {
+            Ok(p) => Some(p / 100.0),
+            Err(_) => {
+                log::error!("Invalid fan percentage {:?}", p);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+fn channel_nr(chan: PwmChannel) -> u8 {
+    match chan {
+        PwmChannel::Pwm0 => 0,
+        PwmChannel::Pwm1 => 1,
+    }
+}
+
+fn device() -> serde_json::Value {
+    let cfg = Config::global();
+
+    json!({
+        "identifiers": [object_id("server")],
+        "name": cfg.name,
+        "model": "Nino",
+        "sw_version": VERSION,
+    })
+}
+
+fn base_topic() -> String {
+    format!("nino/{}", slug())
+}
+
+fn availability_topic() -> String {
+    format!("{}/status", base_topic())
+}
+
+fn sensor_topic(id: SensorId) -> String {
+    format!("{}/sensor/{}/state", base_topic(), id.to_usize())
+}
+
+fn discovery_topic(component: &str, object: &str) -> String {
+    let cfg = Config::global();
+    let prefix = match cfg.mqtt {
+        Some(ref mqtt) => mqtt.discovery_prefix.as_str(),
+        None => "homeassistant",
+    };
+
+    format!("{}/{}/{}/config", prefix, component, object_id(object))
+}
+
+fn object_id(suffix: &str) -> String {
+    format!("nino_{}_{}", slug(), suffix)
+}
+
+fn slug() -> String {
+    slugify(&Config::global().name)
+}
+
+/// A name as Home Assistant object ids want it, [a-z0-9_-]. That also keeps /, + and #
+/// out of topics
+fn slugify(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '_' | '-' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_slugged() {
+        assert_eq!(slugify("nino"), "nino");
+        assert_eq!(slugify("Living Room/Fan #1"), "living_room_fan__1");
+        assert_eq!(slugify("rack-2_top"), "rack-2_top");
+        assert_eq!(slugify("+ünï"), "__n_");
+    }
+
+    #[test]
+    fn fan_topics_are_parsed() {
+        let base = "nino/rack/fan/";
+
+        assert!(matches!(
+            fan_command("nino/rack/fan/0/set", base),
+            Some((PwmChannel::Pwm0, "set"))
+        ));
+        assert!(matches!(
+            fan_command("nino/rack/fan/1/percentage/set", base),
+            Some((PwmChannel::Pwm1, "percentage/set"))
+        ));
+
+        assert!(fan_command("nino/rack/fan/2/set", base).is_none());
+        assert!(fan_command("nino/other/fan/0/set", base).is_none());
+        assert!(fan_command("nino/rack/fan/0", base).is_none());
+    }
+
+    #[test]
+    fn fan_commands_set_the_duty() {
+        let never = || panic!("The saved duty is not needed");
+
+        assert_eq!(fan_duty("set", "OFF", never), Some(0.0));
+        assert_eq!(fan_duty("percentage/set", "55", never), Some(0.55));
+        assert_eq!(fan_duty("percentage/set", "0", never), Some(0.0));
+        assert_eq!(fan_duty("percentage/set", "fast", never), None);
+        assert_eq!(fan_duty("set", "toggle", never), None);
+        assert_eq!(fan_duty("state", "ON", never), None);
+
+        // ON only turns a stopped fan on, at full speed
+        assert_eq!(fan_duty("set", "ON", || 0.0), Some(1.0));
+        assert_eq!(fan_duty("set", "ON", || 0.4), None);
+    }
+}
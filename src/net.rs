@@ -1,160 +1,738 @@
-use std::convert::{TryFrom, TryInto};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Result;
+use nino::protocol::{proto, receive_package, send_package, MessageId, PROTOCOL_VERSION};
 use prost::Message;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
 
 use crate::{
-    sensor::{SensorId, SensorMessage, Sensors},
+    auth::{self, Access},
+    drop::AbortOnDrop,
+    federation::Peers,
+    sensor::{
+        library::{self, Library},
+        script, Sensor, SensorId, SensorMessage, Sensors, Trigger, MIN_RATE,
+    },
     Config, Global, VERSION,
 };
 
-mod proto {
-    include!(concat!(env!("OUT_DIR"), "/nino.net.rs"));
-}
-
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-enum MessageId {
-    Hello = 0,
-    Ready = 1,
-    Value = 2,
-    Sensors = 3,
-    SensorConfig = 4,
-    AddSensor = 5,
-    Pwm = 6,
-}
-
-impl TryFrom<u16> for MessageId {
-    type Error = anyhow::Error;
-
-    fn try_from(value: u16) -> Result<Self, anyhow::Error> {
-        Ok(match value {
-            0 => MessageId::Hello,
-            1 => MessageId::Ready,
-            2 => MessageId::Value,
-            3 => MessageId::Sensors,
-            4 => MessageId::SensorConfig,
-            5 => MessageId::AddSensor,
-            6 => MessageId::Pwm,
-            _ => anyhow::bail!("{} does not match MessageId", value),
-        })
+/// Everything this server can do that a client has to opt into
+pub const CAPABILITIES: &[proto::Capability] = &[
+    proto::Capability::Acks,
+    proto::Capability::Deltas,
+    proto::Capability::Subscribe,
+    proto::Capability::Heartbeat,
+    proto::Capability::External,
+    proto::Capability::ValidateScript,
+    proto::Capability::Modules,
+];
+
+/// The lowest role a client needs to send the message
+fn required_access(id: MessageId) -> Access {
+    match id {
+        MessageId::Pwm | MessageId::PushValue => Access::Operator,
+        MessageId::SensorConfig
+        | MessageId::AddSensor
+        | MessageId::RemoveSensor
+        | MessageId::ListClients
+        | MessageId::ValidateScript
+        | MessageId::SetModule
+        | MessageId::RemoveModule => Access::Admin,
+        _ => Access::ReadOnly,
+    }
+}
+
+/// All connected clients
+#[derive(Debug, Default)]
+pub struct Clients {
+    next_id: AtomicU64,
+    connected: dashmap::DashMap<u64, Arc<ClientStats>>,
+}
+
+#[derive(Debug)]
+pub struct ClientStats {
+    pub addr: SocketAddr,
+    pub access: Access,
+    pub connected: SystemTime,
+    /// Updates the client was too slow to receive
+    pub dropped: AtomicU64,
+    pub resyncs: AtomicU64,
+}
+
+impl Clients {
+    fn register(&self, addr: SocketAddr, access: Access) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(ClientStats {
+            addr,
+            access,
+            connected: SystemTime::now(),
+            dropped: AtomicU64::new(0),
+            resyncs: AtomicU64::new(0),
+        });
+
+        self.connected.insert(id, stats.clone());
+
+        Registration { id, stats }
+    }
+
+    pub fn iter(&self) -> dashmap::iter::Iter<'_, u64, Arc<ClientStats>> {
+        self.connected.iter()
     }
 }
 
-pub async fn handle(
-    mut socket: TcpStream,
+/// Keeps a client in the list of connected clients for as long as it lives
+struct Registration {
+    id: u64,
+    stats: Arc<ClientStats>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        Clients::global().connected.remove(&self.id);
+    }
+}
+
+/// What was agreed with a client during the handshake and what it subscribed to
+struct Session {
+    access: Access,
+    capabilities: Vec<proto::Capability>,
+    /// None when the client wants every value
+    subscriptions: Option<HashMap<SensorId, Subscription>>,
+}
+
+impl Session {
+    fn supports(&self, capability: proto::Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// The value to send to the client for a sensor update, if any
+    fn offer(&mut self, id: SensorId, value: f64, now: Instant) -> Option<f64> {
+        match self.subscriptions {
+            Some(ref mut subs) => subs.get_mut(&id)?.offer(value, now),
+            None => Some(value),
+        }
+    }
+
+    /// When the earliest held back value can be sent
+    fn next_flush(&self) -> Option<Instant> {
+        self.subscriptions
+            .as_ref()?
+            .values()
+            .filter_map(Subscription::flush_at)
+            .min()
+    }
+
+    /// Take the held back values that are due
+    fn flush(&mut self, now: Instant) -> Vec<(SensorId, f64)> {
+        match self.subscriptions {
+            Some(ref mut subs) => subs
+                .iter_mut()
+                .filter_map(|(id, sub)| Some((*id, sub.flush(now)?)))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+/// Throttling for one sensor a client subscribed to
+struct Subscription {
+    min_interval: Duration,
+    deadband: f64,
+    last_sent: Option<(Instant, f64)>,
+    pending: Option<f64>,
+}
+
+impl Subscription {
+    fn offer(&mut self, value: f64, now: Instant) -> Option<f64> {
+        let (at, last) = match self.last_sent {
+            Some(sent) => sent,
+            None => return self.sent(value, now),
+        };
+
+        if (value - last).abs() < self.deadband {
+            // Back within the deadband, anything held back is no longer worth sending
+            self.pending = None;
+            return None;
+        }
+
+        if now < at + self.min_interval {
+            self.pending = Some(value);
+            return None;
+        }
+
+        self.sent(value, now)
+    }
+
+    fn flush_at(&self) -> Option<Instant> {
+        let (at, _) = self.last_sent?;
+        self.pending.map(|_| at + self.min_interval)
+    }
+
+    fn flush(&mut self, now: Instant) -> Option<f64> {
+        if self.flush_at()? > now {
+            return None;
+        }
+
+        let value = self.pending.take()?;
+        self.sent(value, now)
+    }
+
+    fn sent(&mut self, value: f64, now: Instant) -> Option<f64> {
+        self.last_sent = Some((now, value));
+        self.pending = None;
+        Some(value)
+    }
+}
+
+/// Serve a client on a plain or TLS stream, `certificate` is the access granted
+/// by a verified TLS client certificate
+pub async fn handle<S>(
+    socket: S,
+    addr: SocketAddr,
+    certificate: Option<Access>,
     broadcast: Arc<tokio::sync::broadcast::Sender<SensorMessage>>,
     pwm: crossbeam_channel::Sender<(crate::PwmChannel, f32)>,
-) -> Result<()> {
-    let (rdr, wrt) = socket.split();
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (rdr, wrt) = tokio::io::split(socket);
 
     let mut rdr = BufReader::new(rdr);
     let mut wrt = BufWriter::new(wrt);
 
     // Say hello to the client
-    send_hello(&mut wrt).await?;
+    let nonce = auth::nonce();
+    send_hello(&nonce, &mut wrt).await?;
+
+    let cfg = Config::global();
 
     // Expect the client to respond with a ready
-    let (rdy, _) = receive_package(&mut rdr).await?;
+    let (rdy, data) = tokio::time::timeout(cfg.idle_timeout, receive_package(&mut rdr))
+        .await
+        .map_err(|_| anyhow::format_err!("{} did not send ready in time", addr))??;
 
-    if rdy != MessageId::Ready {
+    if MessageId::try_from(rdy).ok() != Some(MessageId::Ready) {
         anyhow::bail!("Client did not respond with ready");
     }
 
+    let ready = proto::Ready::decode(data.as_slice())?;
+    let access = auth::authenticate(&nonce, &ready.hmac, &ready.token, certificate)?;
+
+    log::info!("{} authenticated with {:?} access", addr, access);
+
+    let mut session = Session {
+        access,
+        capabilities: ready
+            .capabilities
+            .iter()
+            .filter_map(|c| proto::Capability::from_i32(*c))
+            .filter(|c| CAPABILITIES.contains(c))
+            .collect(),
+        subscriptions: None,
+    };
+
+    // Clients from before negotiation dont know the welcome
+    if ready.protocol_version > 0 {
+        let version = ready.protocol_version.min(PROTOCOL_VERSION);
+        send_welcome(version, &session, &mut wrt).await?;
+    }
+
     send_sensors(&mut wrt).await?;
 
+    let registration = Clients::global().register(addr, access);
     let mut updates = broadcast.subscribe();
 
+    // Reading happens in its own task, a read cancelled half way by select would
+    // leave the stream in the middle of a package
+    let (package_tx, mut packages) = tokio::sync::mpsc::channel(16);
+    let _reader = AbortOnDrop::new(tokio::spawn(async move {
+        loop {
+            let package = receive_package(&mut rdr).await;
+            let failed = package.is_err();
+
+            if package_tx.send(package).await.is_err() || failed {
+                break;
+            }
+        }
+    }));
+
+    // Requests that take a while are answered from their own task through here
+    let (answer_tx, mut answers) = mpsc::unbounded_channel();
+
+    let heartbeat = session.supports(proto::Capability::Heartbeat);
+    let mut ping = tokio::time::interval_at(
+        (Instant::now() + cfg.heartbeat_interval).into(),
+        cfg.heartbeat_interval,
+    );
+    let mut last_seen = Instant::now();
+
     loop {
+        let flush_at = session.next_flush();
+
         tokio::select! {
-            Ok(data) = updates.recv() => {
-                use SensorMessage::*;
-                match data {
-                    Update(id, value) => send_value(id, value, &mut wrt).await?,
-                    Config(_) | Error(_) | ClearError(_) => send_sensors(&mut wrt).await?,
-                    _ => {}
+            update = updates.recv() => match update {
+                Ok(data) => send_update(data, &mut session, &mut wrt).await?,
+                Err(RecvError::Lagged(n)) => {
+                    let stats = &registration.stats;
+                    let dropped = stats.dropped.fetch_add(n, Ordering::Relaxed) + n;
+                    stats.resyncs.fetch_add(1, Ordering::Relaxed);
+
+                    log::warn!(
+                        "{} lagged behind, dropped {} updates ({} total), resyncing",
+                        addr, n, dropped
+                    );
+
+                    // The snapshot has every sensor with its latest values, so the
+                    // client is whole again without the updates it missed
+                    send_sensors(&mut wrt).await?;
                 }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = sleep_until(flush_at), if flush_at.is_some() => {
+                for (id, value) in session.flush(Instant::now()) {
+                    send_value(id, value, &mut wrt).await?;
+                }
+            },
+            _ = ping.tick(), if heartbeat => {
+                if last_seen.elapsed() > cfg.idle_timeout {
+                    log::info!("{} missed its heartbeats, disconnecting", addr);
+                    return Ok(());
+                }
+
+                send_ping(&mut wrt).await?;
             },
-            rdy = receive_package(&mut rdr) => {
+            Some(answer) = answers.recv() => send_answer(answer, &session, &mut wrt).await?,
+            rdy = packages.recv() => {
+                last_seen = Instant::now();
+
                 match rdy {
-                    Ok((id, buffer)) => handle_package(id, buffer, &pwm).await?,
-                    Err(e) => log::error!("Recv error {:?}", e),
+                    Some(Ok((id, buffer))) => match MessageId::try_from(id) {
+                        Ok(id) => {
+                            handle_package(id, buffer, &mut session, &pwm, &answer_tx, &mut wrt)
+                                .await?
+                        }
+                        // Sent by a newer client, the payload is already read so just move on
+                        Err(_) => log::debug!("Skipping unknown message id {}", id),
+                    },
+                    Some(Err(e)) if is_eof(&e) => {
+                        log::info!("{} disconnected", addr);
+                        return Ok(());
+                    }
+                    // The reader stops after an error so the connection is done either way
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
                 }
             }
         }
     }
 }
 
-async fn handle_package(
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .map(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+        .unwrap_or(false)
+}
+
+async fn sleep_until(at: Option<Instant>) {
+    if let Some(at) = at {
+        tokio::time::sleep_until(at.into()).await;
+    }
+}
+
+async fn send_update<T>(
+    message: SensorMessage,
+    session: &mut Session,
+    socket: &mut T,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    use SensorMessage::*;
+
+    if let Update(id, value) = message {
+        return match session.offer(id, value, Instant::now()) {
+            Some(value) => send_value(id, value, socket).await,
+            None => Ok(()),
+        };
+    }
+
+    // Only for the scheduler, clients see nothing change
+    if let Recompile(_) = message {
+        return Ok(());
+    }
+
+    if !session.supports(proto::Capability::Deltas) {
+        // Older clients only understand full snapshots
+        return send_sensors(socket).await;
+    }
+
+    match message {
+        Update(..) | Recompile(..) => Ok(()),
+        Config(id) => send_sensor_changed(id, socket).await,
+        Error(id) | ClearError(id) => send_sensor_error(id, socket).await,
+        Remove(id) => send_sensor_removed(id, socket).await,
+    }
+}
+
+/// Why a request was not applied, sent back to the client as an error
+#[derive(Debug)]
+pub struct Rejection(pub proto::error::Code, pub String);
+
+/// What a request handled in its own task answers
+enum Answer {
+    /// Acked or sent as an error, like the requests that are applied right away
+    Outcome(MessageId, u32, Result<Option<SensorId>, Rejection>),
+    ScriptResult(proto::ScriptResult),
+}
+
+impl From<prost::DecodeError> for Rejection {
+    fn from(e: prost::DecodeError) -> Self {
+        Rejection(proto::error::Code::InvalidRequest, format!("{}", e))
+    }
+}
+
+async fn handle_package<T>(
     id: MessageId,
     data: Vec<u8>,
+    session: &mut Session,
     pwm: &crossbeam_channel::Sender<(crate::PwmChannel, f32)>,
-) -> Result<()> {
+    answers: &mpsc::UnboundedSender<Answer>,
+    socket: &mut T,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    // All requests share the request_id field so it can be found before we know if the
+    // rest of the message is valid
+    let request_id = proto::Request::decode(data.as_slice())
+        .map(|r| r.request_id)
+        .unwrap_or(0);
+
+    let access = session.access;
+
+    let res = if access < required_access(id) {
+        log::warn!("Refused {:?} from a client with {:?} access", id, access);
+
+        let message = format!("{:?} requires {:?} access", id, required_access(id));
+        Err(Rejection(proto::error::Code::PermissionDenied, message))
+    } else if id == MessageId::Ping {
+        let ping = proto::Ping::decode(data.as_slice()).unwrap_or_default();
+        return send_pong(ping.nonce, socket).await;
+    } else if id == MessageId::Pong {
+        // Only here to keep the connection alive
+        return Ok(());
+    } else if id == MessageId::ListClients {
+        // Answered with the list itself rather than an ack
+        let list = proto::ListClients::decode(data.as_slice()).unwrap_or_default();
+        return send_clients(list.request_id, socket).await;
+    } else if id == MessageId::ValidateScript {
+        // Answered with the result rather than an ack. Scripts may run until the
+        // script timeout, keep that off the runtime and the client loop
+        let validate = proto::ValidateScript::decode(data.as_slice()).unwrap_or_default();
+        let answers = answers.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = answers.send(Answer::ScriptResult(script_result(validate)));
+        });
+        return Ok(());
+    } else if id == MessageId::ListModules {
+        // Answered with the list itself rather than an ack
+        let list = proto::ListModules::decode(data.as_slice()).unwrap_or_default();
+        return send_modules(list.request_id, socket).await;
+    } else if id == MessageId::SetModule {
+        // Checking a module runs it, keep that off the runtime and the client loop
+        match proto::SetModule::decode(data.as_slice()) {
+            Ok(set) => {
+                let answers = answers.clone();
+                tokio::task::spawn_blocking(move || {
+                    let _ = answers.send(Answer::Outcome(id, request_id, set_module(set)));
+                });
+                return Ok(());
+            }
+            Err(e) => Err(e.into()),
+        }
+    } else if let Some(route) = Peers::global().route(id, &data) {
+        // Requests for a federated peer are answered when the peer answers, the
+        // client is served meanwhile
+        match route {
+            Ok(forward) => {
+                let answers = answers.clone();
+                tokio::spawn(async move {
+                    let res = Peers::global().forward(forward).await;
+                    // Fails only when the client is gone
+                    let _ = answers.send(Answer::Outcome(id, request_id, res));
+                });
+                return Ok(());
+            }
+            Err(rejection) => Err(rejection),
+        }
+    } else {
+        apply_package(id, &data, session, pwm)
+    };
+
+    send_outcome(id, request_id, res, session, socket).await
+}
+
+async fn send_answer<T>(answer: Answer, session: &Session, socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    match answer {
+        Answer::Outcome(id, request_id, res) => {
+            send_outcome(id, request_id, res, session, socket).await
+        }
+        Answer::ScriptResult(result) => {
+            send_package(socket, MessageId::ScriptResult, result).await
+        }
+    }
+}
+
+/// Ack a request or send why it failed, as far as the client understands either
+async fn send_outcome<T>(
+    id: MessageId,
+    request_id: u32,
+    res: Result<Option<SensorId>, Rejection>,
+    session: &Session,
+    socket: &mut T,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    if !session.supports(proto::Capability::Acks) {
+        match res {
+            // Every client has to learn it is not allowed, without the request_id it
+            // does not know about
+            Err(Rejection(code, message)) if code == proto::error::Code::PermissionDenied => {
+                send_error(0, id, code, message, socket).await?
+            }
+            Err(Rejection(code, message)) => {
+                log::debug!("{:?} failed with {:?}: {}", id, code, message)
+            }
+            Ok(_) => {}
+        }
+
+        return Ok(());
+    }
+
+    match res {
+        Ok(sensor_id) => send_ack(request_id, sensor_id, socket).await?,
+        Err(Rejection(code, message)) => {
+            send_error(request_id, id, code, message, socket).await?
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply a request, returns the sensor it created or changed
+fn apply_package(
+    id: MessageId,
+    data: &[u8],
+    session: &mut Session,
+    pwm: &crossbeam_channel::Sender<(crate::PwmChannel, f32)>,
+) -> Result<Option<SensorId>, Rejection> {
+    use proto::error::Code;
+
     let sensors = Sensors::global();
 
     match id {
         MessageId::SensorConfig => {
-            let cfg = proto::SensorConfig::decode(data.as_slice())?;
+            let cfg = proto::SensorConfig::decode(data)?;
             let id = SensorId::from_usize(cfg.id as usize);
 
             let rate = cfg
                 .optional_rate
                 .map(|proto::sensor_config::OptionalRate::Rate(r)| r as usize);
+            if let Some(r) = rate.filter(|r| *r < MIN_RATE) {
+                let message = format!("Rate {} ms is below the least of {} ms", r, MIN_RATE);
+                return Err(Rejection(Code::InvalidRequest, message));
+            }
             let source = cfg
                 .optional_source
                 .map(|proto::sensor_config::OptionalSource::Source(s)| s.into());
+            let trigger = match cfg.optional_trigger {
+                Some(proto::sensor_config::OptionalTrigger::Trigger(t)) => {
+                    match proto::Trigger::from_i32(t) {
+                        Some(t) => Some(t.into()),
+                        None => {
+                            let message = format!("Unknown trigger {}", t);
+                            return Err(Rejection(Code::InvalidRequest, message));
+                        }
+                    }
+                }
+                None => None,
+            };
 
-            sensors.reconfigure(&id, cfg.alias, cfg.unit, rate, source);
+            if let Some(cycle) = source
+                .as_deref()
+                .filter(|_| id.is_virtual())
+                .and_then(|src| sensors.dependency_cycle(&id, src))
+            {
+                let message = format!("Source would make a dependency cycle {}", cycle);
+                return Err(Rejection(Code::InvalidRequest, message));
+            }
+
+            match sensors.reconfigure(&id, cfg.alias, cfg.unit, rate, source, trigger) {
+                Ok(true) => Ok(Some(id)),
+                Ok(false) => Err(Rejection(Code::NotFound, format!("No sensor {:?}", id))),
+                Err(e) => {
+                    log::error!("Saving sensor config to disk failed {}", e);
+                    Err(Rejection(Code::StorageFailed, format!("{}", e)))
+                }
+            }
         }
         MessageId::AddSensor => {
-            sensors.add_virtual();
+            let add = proto::AddSensor::decode(data)?;
+
+            let res = match proto::add_sensor::Kind::from_i32(add.kind) {
+                Some(proto::add_sensor::Kind::Virtual) => sensors.add_virtual(),
+                Some(proto::add_sensor::Kind::External) => sensors.add_external(),
+                None => {
+                    let message = format!("Unknown sensor kind {}", add.kind);
+                    return Err(Rejection(Code::InvalidRequest, message));
+                }
+            };
+
+            match res {
+                Ok(id) => Ok(Some(id)),
+                Err(e) => {
+                    log::error!("Saving sensor config to disk failed {}", e);
+                    Err(Rejection(Code::StorageFailed, format!("{}", e)))
+                }
+            }
+        }
+        MessageId::RemoveSensor => {
+            let rm = proto::RemoveSensor::decode(data)?;
+            let id = SensorId::from_usize(rm.id as usize);
+
+            match sensors.remove(&id) {
+                Ok(true) => Ok(Some(id)),
+                Ok(false) => Err(Rejection(
+                    Code::NotFound,
+                    format!("No virtual or external sensor {:?}", id),
+                )),
+                Err(e) => {
+                    log::error!("Removing sensor config from disk failed {}", e);
+                    Err(Rejection(Code::StorageFailed, format!("{}", e)))
+                }
+            }
+        }
+        MessageId::RemoveModule => {
+            let rm = proto::RemoveModule::decode(data)?;
+
+            match sensors.module_cycle(&rm.name, None) {
+                Ok(None) => {}
+                Ok(Some(cycle)) => {
+                    let message = format!("Removing would make a dependency cycle {}", cycle);
+                    return Err(Rejection(Code::InvalidRequest, message));
+                }
+                Err(e) => return Err(Rejection(Code::StorageFailed, format!("{}", e))),
+            }
+
+            let res = Library::global()
+                .remove(&rm.name)
+                .and_then(|removed| match removed {
+                    // Its dependents now fail to import it
+                    true => sensors.module_changed(&rm.name).map(|_| true),
+                    false => Ok(false),
+                });
+
+            match res {
+                Ok(true) => Ok(None),
+                Ok(false) => Err(Rejection(Code::NotFound, format!("No module {}", rm.name))),
+                Err(e) => {
+                    log::error!("Removing module from disk failed {}", e);
+                    Err(Rejection(Code::StorageFailed, format!("{}", e)))
+                }
+            }
         }
         MessageId::Pwm => {
-            let p = proto::SetPwm::decode(data.as_slice())?;
+            let p = proto::SetPwm::decode(data)?;
 
             let chan = match p.channel {
                 0 => crate::PwmChannel::Pwm0,
                 1 => crate::PwmChannel::Pwm1,
-                _ => return Ok(()),
+                c => return Err(Rejection(Code::NotFound, format!("No pwm channel {}", c))),
             };
 
             if let Err(e) = pwm.try_send((chan, p.value)) {
                 log::error!("Could not send to PWM\n{:?}", e);
+                return Err(Rejection(Code::Unavailable, format!("{}", e)));
             }
+
+            Ok(None)
         }
-        _ => { /* Simply ignore the rest, we dont deal with them here */ }
-    }
+        MessageId::PushValue => {
+            let push = proto::PushValue::decode(data)?;
+            let id = SensorId::from_usize(push.id as usize);
 
-    Ok(())
+            // Clients and scripts can not make sense of these
+            if !push.value.is_finite() {
+                return Err(Rejection(
+                    Code::InvalidRequest,
+                    format!("{} is not a valid sensor value", push.value),
+                ));
+            }
+
+            match sensors.push(&id, push.value) {
+                true => Ok(Some(id)),
+                false => Err(Rejection(
+                    Code::NotFound,
+                    format!("No external sensor {:?}", id),
+                )),
+            }
+        }
+        MessageId::Subscribe => {
+            let sub = proto::Subscribe::decode(data)?;
+
+            session.subscriptions = match sub.sensors.is_empty() {
+                true => None,
+                false => Some(
+                    sub.sensors
+                        .iter()
+                        .map(|s| {
+                            let subscription = Subscription {
+                                min_interval: Duration::from_millis(s.min_interval as u64),
+                                deadband: s.deadband.abs(),
+                                last_sent: None,
+                                pending: None,
+                            };
+
+                            (SensorId::from_usize(s.id as usize), subscription)
+                        })
+                        .collect(),
+                ),
+            };
+
+            Ok(None)
+        }
+        _ => Err(Rejection(
+            Code::InvalidRequest,
+            format!("{:?} is not a request", id),
+        )),
+    }
 }
 
-async fn send_hello<T>(socket: &mut T) -> Result<()>
+async fn send_hello<T>(nonce: &[u8], socket: &mut T) -> Result<()>
 where
     T: AsyncWrite + Unpin,
 {
     let cfg = Config::global();
-    let database = sled::Db::global();
-
-    let pwm0 = database.get("pwm0").ok().flatten().and_then(|v| {
-        let value: &[u8] = &v;
-        let value = f32::from_be_bytes(value.try_into().ok()?);
-        Some(value)
-    }).unwrap_or(0.6);
-
-    let pwm1 = database.get("pwm1").ok().flatten().and_then(|v| {
-        let value: &[u8] = &v;
-        let value = f32::from_be_bytes(value.try_into().ok()?);
-        Some(value)
-    }).unwrap_or(0.28);
 
     let hello = proto::Hello {
         version: VERSION.into(),
         name: cfg.name.clone(),
         retention: cfg.retention as u32,
-        pwm0,
-        pwm1,
+        pwm0: crate::PwmChannel::Pwm0.saved_duty(),
+        pwm1: crate::PwmChannel::Pwm1.saved_duty(),
+        nonce: nonce.to_vec(),
     };
 
     send_package(socket, MessageId::Hello, hello).await?;
@@ -162,6 +740,185 @@ where
     Ok(())
 }
 
+fn role(access: Access) -> proto::welcome::Role {
+    match access {
+        Access::ReadOnly => proto::welcome::Role::ReadOnly,
+        Access::Operator => proto::welcome::Role::Operator,
+        Access::Admin => proto::welcome::Role::Admin,
+    }
+}
+
+async fn send_clients<T>(request_id: u32, socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let clients = Clients::global()
+        .iter()
+        .map(|c| proto::clients::Client {
+            address: c.addr.to_string(),
+            role: role(c.access) as i32,
+            connected: c
+                .connected
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            dropped: c.dropped.load(Ordering::Relaxed),
+            resyncs: c.resyncs.load(Ordering::Relaxed),
+        })
+        .collect();
+
+    let clients = proto::Clients {
+        clients,
+        request_id,
+    };
+
+    send_package(socket, MessageId::Clients, clients).await?;
+
+    Ok(())
+}
+
+/// Runs the script, so it blocks for up to the script timeout
+fn script_result(validate: proto::ValidateScript) -> proto::ScriptResult {
+    // Without a sensor self_prev has nothing to read and gives 0
+    let id = match validate.optional_id {
+        Some(proto::validate_script::OptionalId::Id(id)) => SensorId::from_usize(id as usize),
+        None => SensorId::Virtual(usize::MAX),
+    };
+
+    let validation = script::validate(id, &validate.source);
+
+    let (value, errors) = match validation.result {
+        Ok(value) => (Some(value), vec![]),
+        Err(e) => (None, vec![e]),
+    };
+
+    proto::ScriptResult {
+        optional_value: value.map(proto::script_result::OptionalValue::Value),
+        dependencies: validation
+            .dependencies
+            .into_iter()
+            .map(|id| id.to_usize() as u32)
+            .collect(),
+        errors: errors
+            .into_iter()
+            .map(|e| proto::script_result::Error {
+                message: e.message,
+                line: e.line as u32,
+                column: e.column as u32,
+            })
+            .collect(),
+        eval_time: validation.eval_time.as_micros() as u32,
+        request_id: validate.request_id,
+    }
+}
+
+/// Runs the module to check it, so it blocks like ValidateScript
+fn set_module(set: proto::SetModule) -> Result<Option<SensorId>, Rejection> {
+    use proto::error::Code;
+
+    if let Err(e) = library::check(&set.name, &set.source) {
+        let message = match e.line {
+            0 => e.message,
+            _ => format!("{}:{} {}", e.line, e.column, e.message),
+        };
+        return Err(Rejection(Code::InvalidRequest, message));
+    }
+
+    match Sensors::global().module_cycle(&set.name, Some(&set.source)) {
+        Ok(None) => {}
+        Ok(Some(cycle)) => {
+            let message = format!("Module would make a dependency cycle {}", cycle);
+            return Err(Rejection(Code::InvalidRequest, message));
+        }
+        Err(e) => return Err(Rejection(Code::StorageFailed, format!("{}", e))),
+    }
+
+    let res = Library::global()
+        .set(&set.name, &set.source)
+        .and_then(|_| Sensors::global().module_changed(&set.name));
+
+    match res {
+        Ok(()) => Ok(None),
+        Err(e) => {
+            log::error!("Saving module to disk failed {}", e);
+            Err(Rejection(Code::StorageFailed, format!("{}", e)))
+        }
+    }
+}
+
+async fn send_modules<T>(request_id: u32, socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let sensors = Sensors::global();
+
+    let mut modules = vec![];
+
+    for (name, source) in Library::global().list()? {
+        let dependents = sensors
+            .module_dependents(&name)?
+            .into_iter()
+            .map(|id| id.to_usize() as u32)
+            .collect();
+
+        modules.push(proto::modules::Module {
+            name,
+            source,
+            dependents,
+        });
+    }
+
+    let modules = proto::Modules {
+        modules,
+        request_id,
+    };
+
+    send_package(socket, MessageId::Modules, modules).await?;
+
+    Ok(())
+}
+
+async fn send_ping<T>(socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let ping = proto::Ping {
+        nonce: rand::random(),
+    };
+
+    send_package(socket, MessageId::Ping, ping).await?;
+
+    Ok(())
+}
+
+async fn send_pong<T>(nonce: u64, socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    send_package(socket, MessageId::Pong, proto::Pong { nonce }).await?;
+
+    Ok(())
+}
+
+async fn send_welcome<T>(version: u32, session: &Session, socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let cfg = Config::global();
+
+    let welcome = proto::Welcome {
+        protocol_version: version,
+        capabilities: session.capabilities.iter().map(|c| *c as i32).collect(),
+        role: role(session.access) as i32,
+        heartbeat_interval: cfg.heartbeat_interval.as_millis() as u32,
+        idle_timeout: cfg.idle_timeout.as_millis() as u32,
+    };
+
+    send_package(socket, MessageId::Welcome, welcome).await?;
+
+    Ok(())
+}
+
 async fn send_value<T>(id: SensorId, value: f64, socket: &mut T) -> Result<()>
 where
     T: AsyncWrite + Unpin,
@@ -176,6 +933,91 @@ where
     Ok(())
 }
 
+async fn send_ack<T>(request_id: u32, sensor_id: Option<SensorId>, socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let ack = proto::Ack {
+        request_id,
+        optional_sensor_id: sensor_id
+            .map(|id| proto::ack::OptionalSensorId::SensorId(id.to_usize() as u32)),
+    };
+
+    send_package(socket, MessageId::Ack, ack).await?;
+
+    Ok(())
+}
+
+async fn send_error<T>(
+    request_id: u32,
+    id: MessageId,
+    code: proto::error::Code,
+    message: String,
+    socket: &mut T,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let error = proto::Error {
+        code: code as i32,
+        message_id: id as u32,
+        message,
+        request_id,
+    };
+
+    send_package(socket, MessageId::Error, error).await?;
+
+    Ok(())
+}
+
+fn sensor_to_proto(id: SensorId, sensor: &Sensor, values: bool) -> proto::sensors::Sensor {
+    proto::sensors::Sensor {
+        id: id.to_usize() as u32,
+        rate: sensor.rate as u32,
+        alias: (&sensor.alias).into(),
+        unit: (&sensor.unit).into(),
+        values: match values {
+            true => sensor.values.iter().copied().collect(),
+            false => vec![],
+        },
+        optional_source: sensor
+            .source
+            .as_ref()
+            .map(|s| proto::sensors::sensor::OptionalSource::Source(s.into())),
+        optional_error: sensor
+            .error
+            .as_ref()
+            .map(|e| proto::sensors::sensor::OptionalError::Error(e.into())),
+        instance: sensor.instance.clone().unwrap_or_default(),
+        dependencies: Sensors::global()
+            .dependencies(&id)
+            .into_iter()
+            .map(|d| d.to_usize() as u32)
+            .collect(),
+        trigger: proto::Trigger::from(sensor.trigger) as i32,
+    }
+}
+
+impl From<proto::Trigger> for Trigger {
+    fn from(trigger: proto::Trigger) -> Self {
+        match trigger {
+            proto::Trigger::Change => Trigger::Change,
+            proto::Trigger::Interval => Trigger::Interval,
+            proto::Trigger::Both => Trigger::Both,
+        }
+    }
+}
+
+impl From<Trigger> for proto::Trigger {
+    fn from(trigger: Trigger) -> Self {
+        match trigger {
+            Trigger::Change => proto::Trigger::Change,
+            Trigger::Interval => proto::Trigger::Interval,
+            Trigger::Both => proto::Trigger::Both,
+        }
+    }
+}
+
 async fn send_sensors<T>(socket: &mut T) -> Result<()>
 where
     T: AsyncWrite + Unpin,
@@ -184,21 +1026,7 @@ where
 
     let data = sensors
         .iter()
-        .map(|o| proto::sensors::Sensor {
-            id: o.key().to_usize() as u32,
-            rate: o.rate as u32,
-            alias: (&o.alias).into(),
-            unit: (&o.unit).into(),
-            values: o.values.iter().map(|v| *v).collect(),
-            optional_source: o
-                .source
-                .as_ref()
-                .map(|s| proto::sensors::sensor::OptionalSource::Source(s.into())),
-            optional_error: o
-                .error
-                .as_ref()
-                .map(|e| proto::sensors::sensor::OptionalError::Error(e.into())),
-        })
+        .map(|o| sensor_to_proto(*o.key(), o.value(), true))
         .collect();
 
     let value = proto::Sensors { sensors: data };
@@ -208,40 +1036,156 @@ where
     Ok(())
 }
 
-async fn receive_package<T>(socket: &mut T) -> Result<(MessageId, Vec<u8>)>
+async fn send_sensor_changed<T>(id: SensorId, socket: &mut T) -> Result<()>
 where
-    T: AsyncRead + Unpin,
+    T: AsyncWrite + Unpin,
 {
-    let message_id = MessageId::try_from(socket.read_u16_le().await?)?;
-    let data_len = (socket.read_u64_le().await?) as usize;
+    let sensor = match Sensors::global().get(&id) {
+        Some(s) => sensor_to_proto(id, &s, false),
+        None => return Ok(()), // Removed in the mean time, the removal follows
+    };
 
-    if data_len > 1024 * 1024 * 10 {
-        // Dont accept a payload over 10 mega bytes
-        anyhow::bail!("Recv data_lengt exceeds maximum {}", data_len);
-    }
+    let changed = proto::SensorChanged {
+        sensor: Some(sensor),
+    };
 
-    let mut out = vec![0; data_len];
-    socket.read_exact(&mut out).await?;
+    send_package(socket, MessageId::SensorChanged, changed).await?;
 
-    Ok((message_id, out))
+    Ok(())
 }
 
-async fn send_package<T, P>(socket: &mut T, id: MessageId, package: P) -> Result<()>
+async fn send_sensor_error<T>(id: SensorId, socket: &mut T) -> Result<()>
 where
     T: AsyncWrite + Unpin,
-    P: prost::Message,
 {
-    let mut buf = Vec::with_capacity(package.encoded_len());
-    package.encode(&mut buf)?;
+    let error = match Sensors::global().get(&id) {
+        Some(s) => s.error.clone(),
+        None => return Ok(()),
+    };
+
+    let error = proto::SensorError {
+        id: id.to_usize() as u32,
+        optional_error: error.map(proto::sensor_error::OptionalError::Error),
+    };
 
-    // Write the message id first
-    socket.write_u16_le(id as u16).await?;
+    send_package(socket, MessageId::SensorError, error).await?;
 
-    // Write the length of the data then the data
-    socket.write_u64_le(buf.len() as u64).await?;
+    Ok(())
+}
 
-    socket.write_all(&mut buf).await?;
-    socket.flush().await?;
+async fn send_sensor_removed<T>(id: SensorId, socket: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let removed = proto::SensorRemoved {
+        id: id.to_usize() as u32,
+    };
+
+    send_package(socket, MessageId::SensorRemoved, removed).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribed(min_interval: u64, deadband: f64) -> Session {
+        let subscription = Subscription {
+            min_interval: Duration::from_millis(min_interval),
+            deadband,
+            last_sent: None,
+            pending: None,
+        };
+
+        Session {
+            access: Access::ReadOnly,
+            capabilities: vec![],
+            subscriptions: Some(vec![(SensorId::Tmp0, subscription)].into_iter().collect()),
+        }
+    }
+
+    #[test]
+    fn requests_need_their_role() {
+        use MessageId::*;
+
+        let operator = [Pwm, PushValue];
+        let admin = [
+            SensorConfig,
+            AddSensor,
+            RemoveSensor,
+            ListClients,
+            ValidateScript,
+            SetModule,
+            RemoveModule,
+        ];
+
+        // Every id, so a new message has to be thought about here
+        for n in 0.. {
+            let id = match MessageId::try_from(n) {
+                Ok(id) => id,
+                Err(_) => break,
+            };
+
+            let expected = if admin.contains(&id) {
+                Access::Admin
+            } else if operator.contains(&id) {
+                Access::Operator
+            } else {
+                Access::ReadOnly
+            };
+
+            assert_eq!(required_access(id), expected, "{:?}", id);
+        }
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn only_subscribed_sensors_are_sent() {
+        let mut session = subscribed(0, 0.0);
+        let now = Instant::now();
+
+        assert_eq!(session.offer(SensorId::Tmp0, 20.0, now), Some(20.0));
+        assert_eq!(session.offer(SensorId::Tmp1, 20.0, now), None);
+
+        session.subscriptions = None;
+        assert_eq!(session.offer(SensorId::Tmp1, 20.0, now), Some(20.0));
+    }
+
+    #[test]
+    fn updates_are_held_back_until_the_interval_passed() {
+        let mut session = subscribed(1000, 0.0);
+        let start = Instant::now();
+
+        assert_eq!(session.offer(SensorId::Tmp0, 20.0, start), Some(20.0));
+        assert_eq!(session.next_flush(), None);
+
+        // Only the newest held back value is sent, once the interval is over
+        assert_eq!(session.offer(SensorId::Tmp0, 21.0, ms(start, 200)), None);
+        assert_eq!(session.offer(SensorId::Tmp0, 22.0, ms(start, 400)), None);
+        assert_eq!(session.next_flush(), Some(ms(start, 1000)));
+        assert_eq!(session.flush(ms(start, 900)), vec![]);
+        assert_eq!(session.flush(ms(start, 1000)), vec![(SensorId::Tmp0, 22.0)]);
+        assert_eq!(session.next_flush(), None);
+
+        assert_eq!(session.offer(SensorId::Tmp0, 23.0, ms(start, 2000)), Some(23.0));
+    }
+
+    #[test]
+    fn changes_within_the_deadband_are_dropped() {
+        let mut session = subscribed(1000, 0.5);
+        let start = Instant::now();
+
+        assert_eq!(session.offer(SensorId::Tmp0, 20.0, start), Some(20.0));
+        assert_eq!(session.offer(SensorId::Tmp0, 20.4, ms(start, 2000)), None);
+        assert_eq!(session.offer(SensorId::Tmp0, 20.6, ms(start, 2000)), Some(20.6));
+
+        // A held back value is forgotten when the sensor goes back to what was sent
+        assert_eq!(session.offer(SensorId::Tmp0, 21.5, ms(start, 2500)), None);
+        assert_eq!(session.offer(SensorId::Tmp0, 20.7, ms(start, 2600)), None);
+        assert_eq!(session.next_flush(), None);
+    }
+}
@@ -0,0 +1,33 @@
+use std::{fs::File, io::BufReader};
+
+use anyhow::Result;
+use tokio_rustls::rustls::{internal::pemfile, Certificate, PrivateKey};
+
+pub fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let mut rdr = BufReader::new(File::open(path)?);
+
+    let certs = pemfile::certs(&mut rdr)
+        .map_err(|_| anyhow::format_err!("Could not parse certificates in {}", path))?;
+
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path);
+    }
+
+    Ok(certs)
+}
+
+pub fn load_key(path: &str) -> Result<PrivateKey> {
+    let parse_err = |_| anyhow::format_err!("Could not parse private key in {}", path);
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(parse_err)?;
+
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+            .map_err(parse_err)?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow::format_err!("No private key found in {}", path))
+}
@@ -0,0 +1,124 @@
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/nino.net.rs"));
+}
+
+/// Bumped whenever net.proto changes in a way clients need to know about
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MessageId {
+    Hello = 0,
+    Ready = 1,
+    Value = 2,
+    Sensors = 3,
+    SensorConfig = 4,
+    AddSensor = 5,
+    Pwm = 6,
+    RemoveSensor = 7,
+    Error = 8,
+    Ack = 9,
+    Welcome = 10,
+    SensorChanged = 11,
+    SensorError = 12,
+    SensorRemoved = 13,
+    Subscribe = 14,
+    ListClients = 15,
+    Clients = 16,
+    Ping = 17,
+    Pong = 18,
+    PushValue = 19,
+    ValidateScript = 20,
+    ScriptResult = 21,
+    ListModules = 22,
+    Modules = 23,
+    SetModule = 24,
+    RemoveModule = 25,
+}
+
+impl TryFrom<u16> for MessageId {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u16) -> Result<Self, anyhow::Error> {
+        Ok(match value {
+            0 => MessageId::Hello,
+            1 => MessageId::Ready,
+            2 => MessageId::Value,
+            3 => MessageId::Sensors,
+            4 => MessageId::SensorConfig,
+            5 => MessageId::AddSensor,
+            6 => MessageId::Pwm,
+            7 => MessageId::RemoveSensor,
+            8 => MessageId::Error,
+            9 => MessageId::Ack,
+            10 => MessageId::Welcome,
+            11 => MessageId::SensorChanged,
+            12 => MessageId::SensorError,
+            13 => MessageId::SensorRemoved,
+            14 => MessageId::Subscribe,
+            15 => MessageId::ListClients,
+            16 => MessageId::Clients,
+            17 => MessageId::Ping,
+            18 => MessageId::Pong,
+            19 => MessageId::PushValue,
+            20 => MessageId::ValidateScript,
+            21 => MessageId::ScriptResult,
+            22 => MessageId::ListModules,
+            23 => MessageId::Modules,
+            24 => MessageId::SetModule,
+            25 => MessageId::RemoveModule,
+            _ => anyhow::bail!("{} does not match MessageId", value),
+        })
+    }
+}
+
+/// The alias of a sensor, prefixed with the instance a federated sensor comes from
+pub fn sensor_name(sensor: &proto::sensors::Sensor) -> String {
+    match sensor.instance.is_empty() {
+        true => sensor.alias.clone(),
+        false => format!("{}/{}", sensor.instance, sensor.alias),
+    }
+}
+
+/// Read the next package, the id is left raw so unknown messages can be skipped
+pub async fn receive_package<T>(socket: &mut T) -> Result<(u16, Vec<u8>)>
+where
+    T: AsyncRead + Unpin,
+{
+    let message_id = socket.read_u16_le().await?;
+    let data_len = (socket.read_u64_le().await?) as usize;
+
+    if data_len > 1024 * 1024 * 10 {
+        // Dont accept a payload over 10 mega bytes
+        anyhow::bail!("Recv data_lengt exceeds maximum {}", data_len);
+    }
+
+    let mut out = vec![0; data_len];
+    socket.read_exact(&mut out).await?;
+
+    Ok((message_id, out))
+}
+
+pub async fn send_package<T, P>(socket: &mut T, id: MessageId, package: P) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+    P: prost::Message,
+{
+    let mut buf = Vec::with_capacity(package.encoded_len());
+    package.encode(&mut buf)?;
+
+    // Write the message id first
+    socket.write_u16_le(id as u16).await?;
+
+    // Write the length of the data then the data
+    socket.write_u64_le(buf.len() as u64).await?;
+
+    socket.write_all(&mut buf).await?;
+    socket.flush().await?;
+
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::RwLock;
+
+use super::SensorId;
+
+/// Which sensors each virtual sensor reads, as found in its compiled source
+#[derive(Debug, Default)]
+pub struct Graph {
+    edges: RwLock<HashMap<SensorId, BTreeSet<SensorId>>>,
+}
+
+impl Graph {
+    /// Replace the dependencies of a virtual sensor
+    pub fn set(&self, id: SensorId, dependencies: BTreeSet<SensorId>) {
+        let mut edges = self.edges.write().expect("Dependency graph poisoned");
+        edges.insert(id, dependencies);
+    }
+
+    pub fn remove(&self, id: &SensorId) {
+        let mut edges = self.edges.write().expect("Dependency graph poisoned");
+        edges.remove(id);
+    }
+
+    pub fn dependencies(&self, id: &SensorId) -> Vec<SensorId> {
+        let edges = self.edges.read().expect("Dependency graph poisoned");
+        edges
+            .get(id)
+            .map(|d| d.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// The cycle `id` would be part of with these dependencies, starting and ending in `id`
+    pub fn cycle(&self, id: SensorId, dependencies: &BTreeSet<SensorId>) -> Option<Vec<SensorId>> {
+        let edges = self.edges.read().expect("Dependency graph poisoned");
+
+        let mut seen = HashSet::new();
+        let mut path = vec![id];
+
+        for dep in dependencies {
+            if find_path(&edges, *dep, id, &mut seen, &mut path) {
+                return Some(path);
+            }
+        }
+
+        None
+    }
+
+    /// Does `id` read another sensor that is itself updated when `changed` is. Then it
+    /// is better off waiting for that one, so it sees all its inputs updated. Only
+    /// sensors for which `updates` is true pass a change on
+    pub fn hears_again<F>(&self, id: &SensorId, changed: SensorId, updates: F) -> bool
+    where
+        F: Fn(&SensorId) -> bool,
+    {
+        let edges = self.edges.read().expect("Dependency graph poisoned");
+
+        let mut reached = HashSet::new();
+        let mut next = vec![changed];
+
+        while let Some(from) = next.pop() {
+            for (dependent, deps) in edges.iter() {
+                if dependent != id
+                    && deps.contains(&from)
+                    && updates(dependent)
+                    && reached.insert(*dependent)
+                {
+                    next.push(*dependent);
+                }
+            }
+        }
+
+        edges
+            .get(id)
+            .map(|deps| deps.iter().any(|d| reached.contains(d)))
+            .unwrap_or(false)
+    }
+}
+
+/// Depth first search from `from` to `to`, the path taken is appended to `path`
+fn find_path(
+    edges: &HashMap<SensorId, BTreeSet<SensorId>>,
+    from: SensorId,
+    to: SensorId,
+    seen: &mut HashSet<SensorId>,
+    path: &mut Vec<SensorId>,
+) -> bool {
+    path.push(from);
+
+    if from == to {
+        return true;
+    }
+
+    if seen.insert(from) {
+        for dep in edges.get(&from).into_iter().flatten() {
+            if find_path(edges, *dep, to, seen, path) {
+                return true;
+            }
+        }
+    }
+
+    path.pop();
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(usize, &[usize])]) -> Graph {
+        let graph = Graph::default();
+        for (id, deps) in edges {
+            let deps = deps.iter().map(|d| SensorId::from_usize(*d)).collect();
+            graph.set(SensorId::from_usize(*id), deps);
+        }
+        graph
+    }
+
+    fn ids(ids: &[usize]) -> Vec<SensorId> {
+        ids.iter().map(|id| SensorId::from_usize(*id)).collect()
+    }
+
+    #[test]
+    fn cycles_are_found() {
+        let g = graph(&[(7, &[0]), (8, &[7]), (9, &[8, 1])]);
+
+        let deps = ids(&[9]).into_iter().collect();
+        assert_eq!(g.cycle(SensorId::Virtual(7), &deps), Some(ids(&[7, 9, 8, 7])));
+
+        let deps = ids(&[7]).into_iter().collect();
+        assert_eq!(g.cycle(SensorId::Virtual(7), &deps), Some(ids(&[7, 7])));
+
+        let deps = ids(&[0, 1]).into_iter().collect();
+        assert_eq!(g.cycle(SensorId::Virtual(7), &deps), None);
+    }
+
+    #[test]
+    fn changes_are_waited_for() {
+        // 10 reads 0 directly and through 8 and 9
+        let g = graph(&[(7, &[1]), (8, &[0]), (9, &[8]), (10, &[9, 0, 8])]);
+
+        let all = |_: &SensorId| true;
+        assert!(g.hears_again(&SensorId::Virtual(10), SensorId::Tmp0, all));
+        assert!(!g.hears_again(&SensorId::Virtual(10), SensorId::Virtual(9), all));
+        assert!(!g.hears_again(&SensorId::Virtual(8), SensorId::Tmp0, all));
+        assert!(!g.hears_again(&SensorId::Virtual(7), SensorId::Tmp1, all));
+    }
+
+    #[test]
+    fn sensors_in_error_are_not_waited_for() {
+        let g = graph(&[(8, &[0]), (9, &[8]), (10, &[9, 0, 8])]);
+
+        // 8 still updates
+        let updates = |id: &SensorId| *id != SensorId::Virtual(9);
+        assert!(g.hears_again(&SensorId::Virtual(10), SensorId::Tmp0, updates));
+
+        // Neither does 9 then, it only reads 8
+        let updates = |id: &SensorId| *id != SensorId::Virtual(8);
+        assert!(!g.hears_again(&SensorId::Virtual(10), SensorId::Tmp0, updates));
+    }
+}
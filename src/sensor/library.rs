@@ -0,0 +1,193 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, ThreadId};
+use std::time::Instant;
+
+use anyhow::Result;
+use rhai::{Engine, EvalAltResult, Module, ModuleResolver, Position, Scope, Shared};
+
+use super::script::{self, Run, ScriptError};
+use super::SensorId;
+use crate::{Config, Global};
+
+/// Rhai modules virtual sensor scripts import with `import "name" as m;`. The sources
+/// are saved, the modules built from them are kept until the library changes
+#[derive(Debug, Default)]
+pub struct Library {
+    built: RwLock<HashMap<String, Shared<Module>>>,
+    /// Bumped on every change, so a module built from an old source is not kept
+    version: AtomicU64,
+    /// The modules being built and by which thread, to refuse a module importing itself
+    building: Mutex<Vec<(ThreadId, String)>>,
+}
+
+impl Library {
+    pub fn source(&self, name: &str) -> Result<Option<String>> {
+        Ok(match tree()?.get(name)? {
+            Some(data) => Some(String::from_utf8(data.to_vec())?),
+            None => None,
+        })
+    }
+
+    /// Every module as name and source, sorted by name
+    pub fn list(&self) -> Result<Vec<(String, String)>> {
+        tree()?
+            .iter()
+            .map(|res| {
+                let (name, source) = res?;
+                Ok((
+                    String::from_utf8(name.to_vec())?,
+                    String::from_utf8(source.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Add or replace a module, it should be checked first
+    pub fn set(&self, name: &str, source: &str) -> Result<()> {
+        tree()?.insert(name, source)?;
+        self.changed();
+
+        Ok(())
+    }
+
+    /// Returns false if there is no such module
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let removed = tree()?.remove(name)?.is_some();
+        self.changed();
+
+        Ok(removed)
+    }
+
+    /// The module and every module that imports it, directly or through other modules
+    pub fn importers(&self, name: &str) -> Result<BTreeSet<String>> {
+        let imports: Vec<(String, BTreeSet<String>)> = self
+            .list()?
+            .into_iter()
+            .map(|(name, source)| (name, script::static_imports(&source)))
+            .collect();
+
+        let mut found: BTreeSet<String> = vec![name.to_string()].into_iter().collect();
+
+        loop {
+            let more: Vec<String> = imports
+                .iter()
+                .filter(|(n, i)| !found.contains(n) && i.iter().any(|i| found.contains(i)))
+                .map(|(n, _)| n.clone())
+                .collect();
+
+            if more.is_empty() {
+                return Ok(found);
+            }
+
+            found.extend(more);
+        }
+    }
+
+    // Modules that import a changed module were built with the old one, so all go
+    fn changed(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        self.built.write().expect("Module library poisoned").clear();
+    }
+
+    fn module(
+        &self,
+        engine: &Engine,
+        name: &str,
+        pos: Position,
+    ) -> Result<Shared<Module>, Box<EvalAltResult>> {
+        {
+            let built = self.built.read().expect("Module library poisoned");
+            if let Some(module) = built.get(name) {
+                return Ok(module.clone());
+            }
+        }
+
+        let version = self.version.load(Ordering::SeqCst);
+
+        let source = match self.source(name) {
+            Ok(Some(source)) => source,
+            Ok(None) => return Err(EvalAltResult::ErrorModuleNotFound(name.into(), pos).into()),
+            Err(e) => return Err(format!("Could not load module {} {}", name, e).into()),
+        };
+
+        let module = self
+            .build(engine, name, &source)
+            .map_err(|e| Box::new(EvalAltResult::ErrorInModule(name.into(), e, pos)))?;
+
+        if self.version.load(Ordering::SeqCst) == version {
+            let mut built = self.built.write().expect("Module library poisoned");
+            built.insert(name.into(), module.clone());
+        }
+
+        Ok(module)
+    }
+
+    fn build(
+        &self,
+        engine: &Engine,
+        name: &str,
+        source: &str,
+    ) -> Result<Shared<Module>, Box<EvalAltResult>> {
+        let building = (thread::current().id(), name.to_string());
+
+        {
+            let mut all = self.building.lock().expect("Module library poisoned");
+            if all.contains(&building) {
+                return Err(format!("Module {} imports itself", name).into());
+            }
+            all.push(building.clone());
+        }
+
+        let module = engine
+            .compile(source)
+            .map_err(Into::into)
+            .and_then(|ast| Module::eval_ast_as_new(Scope::new(), &ast, engine));
+
+        let mut all = self.building.lock().expect("Module library poisoned");
+        all.retain(|b| *b != building);
+
+        Ok(module?.into())
+    }
+}
+
+/// Resolves the imports of scripts from the library
+pub struct Resolver;
+
+impl ModuleResolver for Resolver {
+    fn resolve(
+        &self,
+        engine: &Engine,
+        path: &str,
+        pos: Position,
+    ) -> Result<Shared<Module>, Box<EvalAltResult>> {
+        Library::global().module(engine, path, pos)
+    }
+}
+
+/// Compile and run a module source, imports are resolved from the saved library
+pub fn check(name: &str, source: &str) -> Result<(), ScriptError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(ScriptError {
+            message: "Module names may only have letters, digits and _".into(),
+            line: 0,
+            column: 0,
+        });
+    }
+
+    // Functions that act on the current sensor have none to act on
+    let current = Arc::new(Mutex::new(Run::new(
+        SensorId::Virtual(usize::MAX),
+        Instant::now(),
+    )));
+    let eng = script::engine(current, &Config::global().script_limits);
+
+    Library::global().build(&eng, name, source)?;
+
+    Ok(())
+}
+
+fn tree() -> Result<sled::Tree> {
+    Ok(sled::Db::global().open_tree("script-modules")?)
+}
@@ -1,8 +1,6 @@
 use std::{
-    cell::RefCell,
-    collections::{HashSet, VecDeque},
+    collections::{BTreeSet, HashSet, VecDeque},
     convert::TryInto,
-    rc::Rc,
     thread,
     time::Instant,
 };
@@ -16,14 +14,20 @@ pub mod builtin_facade;
 #[cfg(not(target_arch = "arm"))]
 pub use builtin_facade as builtin;
 
+mod graph;
+pub mod library;
+mod scheduler;
+pub mod script;
+
 use crossbeam_channel::TrySendError;
-use rhai::RegisterResultFn;
 use serde::{Deserialize, Serialize};
 
+pub use scheduler::run_virtual_sensors;
+
 use anyhow::Result;
 use log;
 
-use crate::{drop::DropJoin, Config, Global, Workers};
+use crate::{drop::DropJoin, Config, Global};
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
 pub enum SensorId {
@@ -38,8 +42,21 @@ pub enum SensorId {
     RPM1,
 
     Virtual(usize),
+
+    /// Values are pushed by a client, numbered from EXTERNAL_START
+    External(usize),
+
+    /// Federated from a peer instance, numbered from REMOTE_START
+    Remote(usize),
 }
 
+/// External and remote sensor ids start high enough to never meet virtual sensor ids
+pub const EXTERNAL_START: usize = 1 << 19;
+pub const REMOTE_START: usize = 1 << 20;
+
+/// The least rate in ms, below it interval triggered sensors would run back to back
+pub const MIN_RATE: usize = 10;
+
 impl SensorId {
     pub fn from_usize(nr: usize) -> SensorId {
         use SensorId::*;
@@ -55,6 +72,8 @@ impl SensorId {
             5 => RPM0,
             6 => RPM1,
 
+            nr if nr >= REMOTE_START => Remote(nr),
+            nr if nr >= EXTERNAL_START => External(nr),
             nr => Virtual(nr),
         }
     }
@@ -74,6 +93,8 @@ impl SensorId {
             RPM1 => 6,
 
             Virtual(nr) => nr,
+            External(nr) => nr,
+            Remote(nr) => nr,
         }
     }
 
@@ -87,6 +108,23 @@ impl SensorId {
             _ => false,
         };
     }
+
+    pub fn is_external(&self) -> bool {
+        matches!(self, SensorId::External(_))
+    }
+
+    pub fn is_remote(&self) -> bool {
+        matches!(self, SensorId::Remote(_))
+    }
+
+    /// The sled tree the config of the sensor is saved in
+    fn tree(&self) -> &'static str {
+        match self {
+            SensorId::Virtual(_) => "sensor-virtual",
+            SensorId::External(_) => "sensor-external",
+            _ => "sensor-builtin",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -101,6 +139,40 @@ pub struct Sensor {
 
     #[serde(skip)]
     pub error: Option<String>,
+
+    /// The peer instance a remote sensor is federated from
+    #[serde(skip)]
+    pub instance: Option<String>,
+
+    /// Saved in its own tree, older configs were saved without it
+    #[serde(skip)]
+    pub trigger: Trigger,
+}
+
+/// What makes a virtual sensor evaluate its source
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// A sensor it reads got a new value, at most once every `rate` ms
+    Change,
+    /// Every `rate` ms
+    Interval,
+    Both,
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Trigger::Change
+    }
+}
+
+impl Trigger {
+    pub fn on_change(self) -> bool {
+        self != Trigger::Interval
+    }
+
+    pub fn on_interval(self) -> bool {
+        self != Trigger::Change
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -110,11 +182,16 @@ pub enum SensorMessage {
     Update(SensorId, f64),
     Error(SensorId),
     ClearError(SensorId),
+    /// A library module the source of a virtual sensor imports changed
+    Recompile(SensorId),
 }
 
 #[derive(Debug)]
 pub struct Sensors {
     sensor_storage: dashmap::DashMap<SensorId, Sensor>,
+    /// When a value was last pushed to each external sensor
+    pushed: dashmap::DashMap<SensorId, Instant>,
+    graph: graph::Graph,
     // Subscription stuff
     followers: std::sync::Mutex<Vec<crossbeam_channel::Sender<SensorMessage>>>,
 }
@@ -123,6 +200,8 @@ impl Sensors {
     pub fn new() -> Sensors {
         Sensors {
             sensor_storage: dashmap::DashMap::new(),
+            pushed: dashmap::DashMap::new(),
+            graph: Default::default(),
             followers: std::sync::Mutex::new(vec![]),
         }
     }
@@ -143,6 +222,8 @@ impl Sensors {
                 rate: 1000,
                 source: None,
                 error: None,
+                instance: None,
+                trigger: Default::default(),
             });
 
         self.sensor_storage.insert(SensorId::Tmp0, tmp0);
@@ -157,6 +238,8 @@ impl Sensors {
                 rate: 1000,
                 source: None,
                 error: None,
+                instance: None,
+                trigger: Default::default(),
             });
 
         self.sensor_storage.insert(SensorId::Tmp1, tmp1);
@@ -171,6 +254,8 @@ impl Sensors {
                 rate: 1000,
                 source: None,
                 error: None,
+                instance: None,
+                trigger: Default::default(),
             });
 
         self.sensor_storage.insert(SensorId::Tmp2, tmp2);
@@ -185,6 +270,8 @@ impl Sensors {
                 rate: 1000,
                 source: None,
                 error: None,
+                instance: None,
+                trigger: Default::default(),
             });
 
         self.sensor_storage.insert(SensorId::Tmp3, tmp3);
@@ -199,6 +286,8 @@ impl Sensors {
                 rate: 3000,
                 source: None,
                 error: None,
+                instance: None,
+                trigger: Default::default(),
             });
 
         self.sensor_storage.insert(SensorId::RPi, rpi);
@@ -213,6 +302,8 @@ impl Sensors {
                 rate: 6000,
                 source: None,
                 error: None,
+                instance: None,
+                trigger: Default::default(),
             });
 
         self.sensor_storage.insert(SensorId::RPM0, rpm0);
@@ -227,11 +318,14 @@ impl Sensors {
                 rate: 6000,
                 source: None,
                 error: None,
+                instance: None,
+                trigger: Default::default(),
             });
 
         self.sensor_storage.insert(SensorId::RPM1, rpm1);
 
         let virt = database.open_tree("sensor-virtual")?;
+        let triggers = database.open_tree("sensor-trigger")?;
 
         for res in virt.iter() {
             let (key, value) = res?;
@@ -240,10 +334,30 @@ impl Sensors {
             let id = usize::from_be_bytes(id.try_into()?);
             let id = SensorId::from_usize(id);
 
+            let mut sensor: Sensor = bincode::deserialize(&value)?;
+
+            if let Some(trigger) = triggers.get(&key)? {
+                sensor.trigger = bincode::deserialize(&trigger)?;
+            }
+
+            self.set_dependencies(id, sensor.source.as_deref());
+            self.sensor_storage.insert(id, sensor);
+        }
+
+        let external = database.open_tree("sensor-external")?;
+
+        for res in external.iter() {
+            let (key, value) = res?;
+
+            let id: &[u8] = &key;
+            let id = usize::from_be_bytes(id.try_into()?);
+            let id = SensorId::from_usize(id);
+
             let sensor = bincode::deserialize(&value)?;
 
             self.sensor_storage.insert(id, sensor);
-            start_virtual_worker(id);
+            // Stale until the first value is pushed after a restart
+            self.pushed.insert(id, Instant::now());
         }
 
         Ok(())
@@ -253,6 +367,7 @@ impl Sensors {
         let max = self
             .sensor_storage
             .iter()
+            .filter(|r| r.key().to_usize() < EXTERNAL_START)
             .map(|r| r.key().to_usize())
             .max()
             .unwrap_or(6);
@@ -260,7 +375,20 @@ impl Sensors {
         SensorId::Virtual(max + 1)
     }
 
-    pub fn add_virtual(&self) {
+    fn next_external_id(&self) -> SensorId {
+        let max = self
+            .sensor_storage
+            .iter()
+            .filter(|r| r.key().is_external())
+            .map(|r| r.key().to_usize() + 1)
+            .max()
+            .unwrap_or(EXTERNAL_START);
+
+        SensorId::External(max)
+    }
+
+    /// Add a new virtual sensor, it is only added if its config could be saved
+    pub fn add_virtual(&self) -> Result<SensorId> {
         let id = self.next_virt_id();
         let sensor = Sensor {
             alias: format!("{:?}", id),
@@ -269,19 +397,114 @@ impl Sensors {
             rate: 1000,
             source: Some("sensor(0)".into()),
             error: None,
+            instance: None,
+            trigger: Default::default(),
         };
 
-        if let Err(m) = self.save_sensor(&id, &sensor) {
-            log::error!("Saving sensor config to disk failed {}", m);
-        }
+        self.save_sensor(&id, &sensor)?;
+
+        self.set_dependencies(id, sensor.source.as_deref());
+        self.sensor_storage.insert(id, sensor);
+
+        log::trace!("Added sensor {:?}", id);
+
+        self.broadcast(SensorMessage::Config(id));
+
+        Ok(id)
+    }
+
+    /// Add a new sensor whose values are pushed by clients, it is only added if its
+    /// config could be saved. It is in error when no value was pushed for `rate` ms
+    pub fn add_external(&self) -> Result<SensorId> {
+        let id = self.next_external_id();
+        let sensor = Sensor {
+            alias: format!("{:?}", id),
+            unit: "?".into(),
+            values: Default::default(),
+            rate: 60_000,
+            source: None,
+            error: None,
+            instance: None,
+            trigger: Default::default(),
+        };
+
+        self.save_sensor(&id, &sensor)?;
 
         self.sensor_storage.insert(id, sensor);
+        self.pushed.insert(id, Instant::now());
 
         log::trace!("Added sensor {:?}", id);
 
         self.broadcast(SensorMessage::Config(id));
 
-        start_virtual_worker(id);
+        Ok(id)
+    }
+
+    /// Set the value of an external sensor, returns false if there is no such external sensor.
+    /// Unlike `set` negative values are kept, outdoor temperatures go below zero. Values
+    /// that are not finite should be refused before
+    pub fn push(&self, key: &SensorId, value: f64) -> bool {
+        if !key.is_external() || !self.sensor_storage.contains_key(key) {
+            return false;
+        }
+
+        self.pushed.insert(*key, Instant::now());
+        self.store(key, value);
+
+        let stale = self.get(key).map(|s| s.error.is_some()).unwrap_or(false);
+        if stale {
+            self.clear_error(key);
+        }
+
+        true
+    }
+
+    /// Remove a virtual or external sensor and its saved config, returns false if
+    /// there was no such sensor. Builtin sensors can not be removed
+    pub fn remove(&self, key: &SensorId) -> Result<bool> {
+        if !(key.is_virtual() || key.is_external()) || !self.sensor_storage.contains_key(key) {
+            return Ok(false);
+        }
+
+        let database = sled::Db::global();
+        database.open_tree(key.tree())?.remove(key.to_be_bytes())?;
+        database.open_tree("sensor-trigger")?.remove(key.to_be_bytes())?;
+        script::clear_state(*key)?;
+
+        self.sensor_storage.remove(key);
+        self.pushed.remove(key);
+        self.graph.remove(key);
+
+        log::trace!("Removed sensor {:?}", key);
+
+        self.broadcast(SensorMessage::Remove(*key));
+
+        Ok(true)
+    }
+
+    /// Add or update a sensor federated from a peer. Remote sensors are not saved, the
+    /// peer sends them again when it is reconnected
+    pub fn insert_remote(&self, key: &SensorId, mut sensor: Sensor) {
+        if let Some(old) = self.sensor_storage.get(key) {
+            // Changes from the peer leave out the values
+            if sensor.values.is_empty() {
+                sensor.values = old.values.clone();
+            }
+        }
+
+        self.sensor_storage.insert(*key, sensor);
+        self.broadcast(SensorMessage::Config(*key));
+    }
+
+    /// Remove a sensor federated from a peer, returns false if there was no such sensor
+    pub fn remove_remote(&self, key: &SensorId) -> bool {
+        if !key.is_remote() || self.sensor_storage.remove(key).is_none() {
+            return false;
+        }
+
+        self.broadcast(SensorMessage::Remove(*key));
+
+        true
     }
 
     pub fn set_error(&self, key: &SensorId, error: String) {
@@ -302,6 +525,8 @@ impl Sensors {
         }
     }
 
+    /// Change the config of a sensor, it is only applied if it could be saved.
+    /// Returns false if there is no such sensor
     pub fn reconfigure(
         &self,
         key: &SensorId,
@@ -309,36 +534,158 @@ impl Sensors {
         unit: String,
         rate: Option<usize>,
         source: Option<String>,
-    ) {
+        trigger: Option<Trigger>,
+    ) -> Result<bool> {
         log::trace!("Reconfig {:?}, alias={}, unit={}", key, alias, unit,);
 
-        if let Some(mut s) = self.sensor_storage.get_mut(key) {
-            s.alias = alias;
-            s.unit = unit;
+        // Remote sensors are configured on the peer they come from
+        if key.is_remote() {
+            return Ok(false);
+        }
 
-            if key.is_virtual() {
-                s.rate = rate.unwrap_or(1000);
-                s.source = source;
-            }
+        // Found before the sensor is locked, remote() in a source looks through all sensors
+        let dependencies = match key.is_virtual() {
+            true => Some(source.as_deref().map(script::static_dependencies)),
+            false => None,
+        };
+
+        let mut s = match self.sensor_storage.get_mut(key) {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+
+        let (rate, source, trigger) = if key.is_virtual() {
+            (rate.unwrap_or(1000), source, trigger.unwrap_or(s.trigger))
+        } else if key.is_external() {
+            (rate.unwrap_or(s.rate), None, s.trigger)
+        } else {
+            (s.rate, s.source.clone(), s.trigger)
+        };
+
+        let updated = Sensor {
+            alias,
+            unit,
+            rate,
+            source,
+            values: VecDeque::new(),
+            error: None,
+            instance: None,
+            trigger,
+        };
+
+        self.save_sensor(key, &updated)?;
+
+        if let Some(dependencies) = dependencies {
+            self.graph.set(*key, dependencies.unwrap_or_default());
+        }
+
+        s.alias = updated.alias;
+        s.unit = updated.unit;
+        s.rate = updated.rate;
+        s.source = updated.source;
+        s.trigger = updated.trigger;
+
+        self.broadcast(SensorMessage::Config(*key));
+
+        Ok(true)
+    }
+
+    fn set_dependencies(&self, id: SensorId, source: Option<&str>) {
+        let dependencies = source.map(script::static_dependencies).unwrap_or_default();
+        self.graph.set(id, dependencies);
+    }
 
-            if let Err(m) = self.save_sensor(key, &s) {
-                log::error!("Saving sensor config to disk failed {}", m);
+    /// The sensors the source of a virtual sensor reads, as far as can be told without
+    /// running it
+    pub fn dependencies(&self, key: &SensorId) -> Vec<SensorId> {
+        self.graph.dependencies(key)
+    }
+
+    /// Find what a virtual sensor reads again, `remote()` calls are resolved to the
+    /// remote sensors there are now
+    pub fn refresh_dependencies(&self, key: &SensorId) -> HashSet<SensorId> {
+        let source = self.get(key).and_then(|s| s.source.clone());
+        self.set_dependencies(*key, source.as_deref());
+
+        self.dependencies(key).into_iter().collect()
+    }
+
+    /// The dependency cycle a virtual sensor would be part of with this source, like
+    /// "7 -> 8 -> 7"
+    pub fn dependency_cycle(&self, key: &SensorId, source: &str) -> Option<String> {
+        let cycle = self.graph.cycle(*key, &script::static_dependencies(source))?;
+        Some(describe_path(&cycle))
+    }
+
+    /// Virtual sensors that import a library module, directly or through other modules
+    pub fn module_dependents(&self, name: &str) -> Result<Vec<SensorId>> {
+        let importers = library::Library::global().importers(name)?;
+
+        let mut dependents: Vec<SensorId> = self
+            .sensor_storage
+            .iter()
+            .filter(|s| s.key().is_virtual())
+            .filter(|s| {
+                let imports = s.source.as_deref().map(script::static_imports);
+                imports.unwrap_or_default().iter().any(|i| importers.contains(i))
+            })
+            .map(|s| *s.key())
+            .collect();
+        dependents.sort();
+
+        Ok(dependents)
+    }
+
+    /// The dependency cycle a virtual sensor would be part of if a library module had
+    /// this source, or was removed for None
+    pub fn module_cycle(&self, name: &str, module: Option<&str>) -> Result<Option<String>> {
+        for id in self.module_dependents(name)? {
+            let source = match self.get(&id).and_then(|s| s.source.clone()) {
+                Some(source) => source,
+                None => continue,
+            };
+
+            let dependencies = script::dependencies_with_module(&source, name, module);
+            if let Some(cycle) = self.graph.cycle(id, &dependencies) {
+                return Ok(Some(describe_path(&cycle)));
             }
+        }
+
+        Ok(None)
+    }
 
-            self.broadcast(SensorMessage::Config(*key));
+    /// Recompile the virtual sensors that import a module that was changed or removed
+    pub fn module_changed(&self, name: &str) -> Result<()> {
+        for id in self.module_dependents(name)? {
+            // What the module reads may have changed
+            let source = self.get(&id).and_then(|s| s.source.clone());
+            self.set_dependencies(id, source.as_deref());
+
+            self.broadcast(SensorMessage::Recompile(id));
         }
+
+        Ok(())
+    }
+
+    /// The dependency cycle a virtual sensor is part of
+    fn current_cycle(&self, key: &SensorId) -> Option<String> {
+        let dependencies: BTreeSet<SensorId> = self.dependencies(key).into_iter().collect();
+        let cycle = self.graph.cycle(*key, &dependencies)?;
+        Some(describe_path(&cycle))
     }
 
     pub fn save_sensor(&self, key: &SensorId, sensor: &Sensor) -> Result<()> {
         let database = sled::Db::global();
         let data = bincode::serialize(&sensor)?;
 
+        let tree = database.open_tree(key.tree())?;
+        tree.insert(key.to_be_bytes(), data)?;
+
         if key.is_virtual() {
-            let tree = database.open_tree("sensor-virtual")?;
-            tree.insert(key.to_be_bytes(), data)?;
-        } else {
-            let tree = database.open_tree("sensor-builtin")?;
-            tree.insert(key.to_be_bytes(), data)?;
+            let trigger = bincode::serialize(&sensor.trigger)?;
+            database
+                .open_tree("sensor-trigger")?
+                .insert(key.to_be_bytes(), trigger)?;
         }
 
         Ok(())
@@ -349,6 +696,10 @@ impl Sensors {
             return; // Negative values are not real
         }
 
+        self.store(key, value);
+    }
+
+    fn store(&self, key: &SensorId, value: f64) {
         if let Some(mut sensor) = self.sensor_storage.get_mut(&key) {
             let retention = Config::global().retention;
 
@@ -394,8 +745,22 @@ impl Sensors {
     }
 
     pub fn subscribe(&self) -> SensorIterator {
-        let (tx, rx) = crossbeam_channel::bounded(25);
+        let (tx, rx) = crossbeam_channel::bounded(Config::global().subscriber_buffer);
+        self.follow(tx, rx)
+    }
 
+    /// Like `subscribe` but no message is ever dropped, for followers that must not
+    /// miss a config change. They have to keep up, the channel is unbounded
+    pub fn subscribe_lossless(&self) -> SensorIterator {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.follow(tx, rx)
+    }
+
+    fn follow(
+        &self,
+        tx: crossbeam_channel::Sender<SensorMessage>,
+        rx: crossbeam_channel::Receiver<SensorMessage>,
+    ) -> SensorIterator {
         let mut list = self
             .followers
             .lock()
@@ -407,6 +772,40 @@ impl Sensors {
     }
 }
 
+/// Flags external sensors that have not been pushed a value within their rate
+pub fn watch_external() -> Result<DropJoin<()>> {
+    let handle = thread::Builder::new()
+        .name("external-watchdog".into())
+        .stack_size(32 * 1024)
+        .spawn(move || {
+            let sensors = Sensors::global();
+
+            loop {
+                let stale: Vec<(SensorId, u128)> = sensors
+                    .pushed
+                    .iter()
+                    .filter_map(|p| {
+                        let rate = sensors.get(p.key())?.rate as u128;
+
+                        match p.value().elapsed().as_millis() > rate {
+                            true => Some((*p.key(), rate)),
+                            false => None,
+                        }
+                    })
+                    .collect();
+
+                // The error only goes out to clients the first time it is set
+                for (id, rate) in stale {
+                    sensors.set_error(&id, format!("No value pushed for {} ms", rate));
+                }
+
+                thread::sleep(std::time::Duration::from_secs(1));
+            }
+        })?;
+
+    Ok(DropJoin::new(handle))
+}
+
 pub struct SensorIterator {
     rx: crossbeam_channel::Receiver<SensorMessage>,
 }
@@ -425,96 +824,7 @@ impl Iterator for SensorIterator {
     }
 }
 
-fn start_virtual_worker(id: SensorId) {
-    let handle = thread::spawn(move || {
-        let sensors = Sensors::global();
-        let dependecies = Rc::new(RefCell::new(HashSet::new()));
-
-        let mut eng = rhai::Engine::new();
-        let mut rate = sensors.get(&id).map(|s| s.rate).unwrap_or(1000) as u128;
-
-        let deps = dependecies.clone();
-
-        eng.register_result_fn("sensor", move |index: i32| {
-            let id = SensorId::from_usize(index as usize);
-            let sensors = Sensors::global();
-
-            let res = match sensors.get_value(&id) {
-                Some(val) => Ok(val.into()),
-                None => return Err(format!("Could not find {:?}", id).into()),
-            };
-
-            deps.borrow_mut().insert(id);
-
-            res
-        });
-
-        let mut compiled = {
-            match sensors.get(&id).and_then(|s| s.source.clone()) {
-                Some(ref src) => eng.compile(src),
-                None => eng.compile("N/A"),
-            }
-        };
-
-        let mut last = Instant::now();
-
-        'worker: for upd in sensors.subscribe() {
-            match upd {
-                SensorMessage::Remove(s) if s == id => {
-                    log::debug!("Sensor {:?} removed, killing worker", id);
-                    break 'worker;
-                } // Client removed the sensor, let the worker die
-                SensorMessage::Config(s) if s == id => {
-                    if let Some(sensor) = sensors.get(&id) {
-                        rate = sensor.rate as u128;
-                        compiled = match sensor.source {
-                            Some(ref src) => eng.compile(src),
-                            None => eng.compile("N/A"),
-                        };
-
-                        // We dont know which depedencies are now in play
-                        dependecies.borrow_mut().clear();
-
-                        // The user might have fixed the error
-                        log::debug!("Recompile {:?} source", id);
-                    }
-
-                    sensors.clear_error(&id);
-                }
-                SensorMessage::Update(s, _value)
-                    if (dependecies.borrow().contains(&s) || dependecies.borrow().is_empty())
-                        && last.elapsed().as_millis() > rate =>
-                {
-                    dependecies.borrow_mut().clear();
-
-                    let ast = match compiled {
-                        Ok(ref ast) => ast,
-                        Err(ref e) => {
-                            sensors.set_error(&id, format!("{:?}", e));
-                            continue 'worker;
-                        }
-                    };
-
-                    // Run script and update this sensors value
-                    match eng.eval_ast(ast) {
-                        Ok(value) => {
-                            sensors.set(&id, value);
-                            last = Instant::now();
-                        }
-                        Err(e) => {
-                            sensors.set_error(&id, format!("{:?}", e));
-                            continue 'worker;
-                        }
-                    }
-                }
-
-                _ => { /* The other cases we can safely ignore */ }
-            }
-        }
-
-        Ok(())
-    });
-
-    let mut wrk = Workers::global().lock().expect("Cant lock sensor workers");
-    wrk.push((vec![id], DropJoin::new(handle)));
+fn describe_path(path: &[SensorId]) -> String {
+    let ids: Vec<String> = path.iter().map(|id| id.to_usize().to_string()).collect();
+    ids.join(" -> ")
 }
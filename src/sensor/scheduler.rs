@@ -0,0 +1,466 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossbeam_channel::{select, Receiver, Sender};
+use rhai::{Engine, EvalAltResult, Scope, AST};
+
+use super::script::{self, Run};
+use super::{SensorId, SensorMessage, Sensors, Trigger, MIN_RATE};
+use crate::{drop::DropJoin, Config, Global};
+
+/// A virtual sensor as the scheduler keeps it
+struct Script {
+    compiled: Result<Arc<AST>, String>,
+    /// Taken by the evaluation while the script runs
+    scope: Option<Scope<'static>>,
+    /// What the script read the last time it ran
+    read: HashSet<SensorId>,
+    /// What the source reads, as far as is known without running it
+    reads: HashSet<SensorId>,
+    rate: Duration,
+    trigger: Trigger,
+    /// When the last value was set
+    last: Instant,
+    /// When it was last started, none before its first evaluation
+    started: Option<Instant>,
+    /// Something it reads changed since it was last started
+    pending: bool,
+    /// A change was left for another sensor it reads to pass on
+    waiting: bool,
+    cycle: Option<String>,
+    /// Bumped on every reconfigure, so results of the old source are dropped
+    generation: u64,
+}
+
+impl Script {
+    fn running(&self) -> bool {
+        self.scope.is_none()
+    }
+
+    /// Does the script read `changed`
+    fn reads(&self, changed: &SensorId) -> bool {
+        self.read.contains(changed) || self.reads.contains(changed)
+    }
+
+    /// Will it run and set a value when what it reads changes
+    fn follows_changes(&self) -> bool {
+        self.trigger.on_change() && self.compiled.is_ok() && self.cycle.is_none()
+    }
+
+    /// When the script is to run next, if at all
+    fn next_run(&self, now: Instant) -> Option<Instant> {
+        if self.running() || !(self.pending || self.trigger.on_interval()) {
+            return None;
+        }
+
+        // Every script is evaluated once when it is loaded
+        match self.started {
+            Some(started) => Some(started + self.rate),
+            None => Some(now),
+        }
+    }
+}
+
+struct Job {
+    id: SensorId,
+    generation: u64,
+    ast: Arc<AST>,
+    scope: Scope<'static>,
+    last: Instant,
+}
+
+struct Done {
+    id: SensorId,
+    generation: u64,
+    result: Result<f64, Box<EvalAltResult>>,
+    read: HashSet<SensorId>,
+    scope: Scope<'static>,
+}
+
+/// Evaluate all virtual sensors. One scheduler thread keeps every compiled script and
+/// decides what runs, the evaluations themselves run on a small pool of threads
+pub fn run_virtual_sensors() -> Result<DropJoin<()>> {
+    let (jobs_tx, jobs_rx) = crossbeam_channel::unbounded();
+    let (done_tx, done_rx) = crossbeam_channel::unbounded();
+
+    for n in 0..Config::global().script_threads.max(1) {
+        let jobs = jobs_rx.clone();
+        let done = done_tx.clone();
+
+        thread::Builder::new()
+            .name(format!("script-{}", n))
+            .spawn(move || evaluate(jobs, done))?;
+    }
+
+    let handle = thread::Builder::new()
+        .name("virtual-scheduler".into())
+        .spawn(move || schedule(jobs_tx, done_rx))?;
+
+    Ok(DropJoin::new(handle))
+}
+
+/// Runs on the pool, an engine per thread
+fn evaluate(jobs: Receiver<Job>, done: Sender<Done>) {
+    let current = Arc::new(Mutex::new(Run::new(SensorId::Virtual(0), Instant::now())));
+    let eng = script::engine(current.clone(), &Config::global().script_limits);
+
+    for mut job in jobs {
+        *current.lock().expect("Current script poisoned") = Run::new(job.id, job.last);
+
+        let result = script::evaluate(&eng, &job.ast, &mut job.scope);
+        let read = std::mem::take(&mut current.lock().expect("Current script poisoned").read);
+
+        let finished = Done {
+            id: job.id,
+            generation: job.generation,
+            result,
+            read,
+            scope: job.scope,
+        };
+
+        if done.send(finished).is_err() {
+            break; // The scheduler is gone
+        }
+    }
+}
+
+fn schedule(jobs: Sender<Job>, done: Receiver<Done>) -> Result<()> {
+    let sensors = Sensors::global();
+    // A lost config or recompile would leave a script running its old source
+    let updates = sensors.subscribe_lossless();
+
+    // Only compiles, evaluations run on the pool
+    let current = Arc::new(Mutex::new(Run::new(SensorId::Virtual(0), Instant::now())));
+    let eng = script::engine(current, &Config::global().script_limits);
+
+    let mut scripts: HashMap<SensorId, Script> = HashMap::new();
+
+    let virtual_ids: Vec<SensorId> = sensors
+        .iter()
+        .map(|s| *s.key())
+        .filter(SensorId::is_virtual)
+        .collect();
+
+    for id in virtual_ids {
+        load(&eng, &mut scripts, id, false);
+    }
+    find_cycles(&mut scripts);
+
+    loop {
+        let now = Instant::now();
+
+        let timer = match scripts.values().filter_map(|s| s.next_run(now)).min() {
+            Some(at) => crossbeam_channel::at(at),
+            None => crossbeam_channel::never(),
+        };
+
+        select! {
+            recv(updates.rx) -> upd => match upd? {
+                SensorMessage::Remove(id) if id.is_virtual() => {
+                    log::debug!("Sensor {:?} removed, dropping its script", id);
+                    scripts.remove(&id);
+                    passed_nothing(&mut scripts, id);
+                }
+                SensorMessage::Config(id) if id.is_virtual() => {
+                    load(&eng, &mut scripts, id, true);
+
+                    // Changing one sensor can break a cycle others are in
+                    find_cycles(&mut scripts);
+                }
+                // Scripts calling remote() find a remote sensor by its instance and
+                // alias, which only resolve once the peer sent it
+                SensorMessage::Config(id) | SensorMessage::Remove(id) if id.is_remote() => {
+                    remote_changed(&mut scripts, |id| sensors.refresh_dependencies(id));
+                }
+                SensorMessage::Recompile(id) => {
+                    recompile(&eng, &mut scripts, id);
+                    find_cycles(&mut scripts);
+                }
+                SensorMessage::Update(changed, _value) => changes(&mut scripts, changed),
+                _ => { /* The other cases we can safely ignore */ }
+            },
+            recv(done) -> finished => finish(&mut scripts, finished?),
+            recv(timer) -> _ => { /* Some script is due */ }
+        }
+
+        dispatch(&jobs, &mut scripts)?;
+    }
+}
+
+/// Compile the source of a virtual sensor. A reconfigured script starts over without
+/// its variables
+fn load(eng: &Engine, scripts: &mut HashMap<SensorId, Script>, id: SensorId, reset: bool) {
+    let sensors = Sensors::global();
+
+    let (source, rate, trigger) = match sensors.get(&id) {
+        Some(s) => (s.source.clone(), s.rate as u64, s.trigger),
+        None => return,
+    };
+
+    log::debug!("Compile {:?} source", id);
+
+    let compiled = eng
+        .compile(source.as_deref().unwrap_or("N/A"))
+        .map(Arc::new)
+        .map_err(|e| format!("{:?}", e));
+
+    let scope = match reset {
+        true => {
+            if let Err(e) = script::clear_state(id) {
+                log::error!("Could not clear script state of {:?} {}", id, e);
+            }
+            Scope::new()
+        }
+        false => script::restore_state(id),
+    };
+
+    let (generation, last, started) = match scripts.get(&id) {
+        Some(s) => (s.generation + 1, s.last, s.started),
+        None => (0, Instant::now(), None),
+    };
+
+    scripts.insert(
+        id,
+        Script {
+            compiled,
+            scope: Some(scope),
+            read: HashSet::new(),
+            reads: sensors.dependencies(&id).into_iter().collect(),
+            // Saved before the rate was checked, it may be lower
+            rate: Duration::from_millis(rate.max(MIN_RATE as u64)),
+            trigger,
+            last,
+            started,
+            pending: true,
+            waiting: false,
+            cycle: None,
+            generation,
+        },
+    );
+
+    // The user might have fixed the error
+    if reset {
+        sensors.clear_error(&id);
+    }
+}
+
+/// Compile again after a module it imports changed, it keeps its variables and runs
+/// with the new module right away
+fn recompile(eng: &Engine, scripts: &mut HashMap<SensorId, Script>, id: SensorId) {
+    let sensors = Sensors::global();
+
+    let (script, source) = match (scripts.get_mut(&id), sensors.get(&id)) {
+        (Some(script), Some(s)) => (script, s.source.clone()),
+        _ => return,
+    };
+
+    log::debug!("Recompile {:?} source", id);
+
+    script.compiled = eng
+        .compile(source.as_deref().unwrap_or("N/A"))
+        .map(Arc::new)
+        .map_err(|e| format!("{:?}", e));
+    script.reads = sensors.dependencies(&id).into_iter().collect();
+    script.pending = true;
+
+    sensors.clear_error(&id);
+}
+
+/// Saved before cycles were refused, a sensor in one is not evaluated
+fn find_cycles(scripts: &mut HashMap<SensorId, Script>) {
+    let sensors = Sensors::global();
+
+    for (id, script) in scripts.iter_mut() {
+        script.cycle = sensors.current_cycle(id);
+    }
+}
+
+/// Take on what the scripts read after the remote sensors changed, a script that now
+/// reads something else runs again
+fn remote_changed<F>(scripts: &mut HashMap<SensorId, Script>, dependencies: F)
+where
+    F: Fn(&SensorId) -> HashSet<SensorId>,
+{
+    for (id, script) in scripts.iter_mut() {
+        let reads = dependencies(id);
+
+        if reads != script.reads {
+            script.reads = reads;
+            script.pending = true;
+        }
+    }
+}
+
+/// Mark the scripts that are triggered by the changed sensor
+fn changes(scripts: &mut HashMap<SensorId, Script>, changed: SensorId) {
+    let sensors = Sensors::global();
+
+    let following: HashSet<SensorId> = scripts
+        .iter()
+        .filter(|(_, s)| s.follows_changes())
+        .map(|(id, _)| *id)
+        .collect();
+
+    for (id, script) in scripts.iter_mut() {
+        if !script.trigger.on_change() || !script.reads(&changed) {
+            continue;
+        }
+
+        // It runs when the other sensors it reads have caught up with the change
+        match sensors
+            .graph
+            .hears_again(id, changed, |d| following.contains(d))
+        {
+            true => script.waiting = true,
+            false => script.pending = true,
+        }
+    }
+}
+
+/// A script that ended without a value passes no change on, so what waited for it runs
+fn passed_nothing(scripts: &mut HashMap<SensorId, Script>, id: SensorId) {
+    for script in scripts.values_mut() {
+        if script.waiting && script.reads(&id) {
+            script.waiting = false;
+            script.pending = true;
+        }
+    }
+}
+
+/// Start the scripts that are due
+fn dispatch(jobs: &Sender<Job>, scripts: &mut HashMap<SensorId, Script>) -> Result<()> {
+    let sensors = Sensors::global();
+    let now = Instant::now();
+
+    let mut due: Vec<SensorId> = scripts
+        .iter()
+        .filter(|(_, s)| s.next_run(now).map(|at| at <= now).unwrap_or(false))
+        .map(|(id, _)| *id)
+        .collect();
+    due.sort();
+
+    for id in due {
+        let script = scripts.get_mut(&id).expect("Due script is missing");
+
+        script.pending = false;
+        script.waiting = false;
+        script.started = Some(now);
+
+        if let Some(ref cycle) = script.cycle {
+            sensors.set_error(&id, format!("Dependency cycle {}", cycle));
+            passed_nothing(scripts, id);
+            continue;
+        }
+
+        let ast = match script.compiled {
+            Ok(ref ast) => ast.clone(),
+            Err(ref e) => {
+                sensors.set_error(&id, e.clone());
+                passed_nothing(scripts, id);
+                continue;
+            }
+        };
+
+        let job = Job {
+            id,
+            generation: script.generation,
+            ast,
+            scope: script.scope.take().expect("Idle script has no scope"),
+            last: script.last,
+        };
+
+        jobs.send(job)?;
+    }
+
+    Ok(())
+}
+
+fn finish(scripts: &mut HashMap<SensorId, Script>, done: Done) {
+    let sensors = Sensors::global();
+
+    let script = match scripts.get_mut(&done.id) {
+        Some(s) if s.generation == done.generation => s,
+        _ => return, // Removed or reconfigured while it ran
+    };
+
+    script.scope = Some(done.scope);
+
+    match done.result {
+        Ok(value) => {
+            script.read = done.read;
+            sensors.set(&done.id, value);
+            script.last = Instant::now();
+
+            let scope = script.scope.as_ref().expect("Scope was just put back");
+            if let Err(e) = script::save_state(done.id, scope) {
+                log::error!("Could not save script state of {:?} {}", done.id, e);
+            }
+
+            // Dropped by the sensor, nobody hears of it
+            if value < 0.0 || value.is_nan() {
+                passed_nothing(scripts, done.id);
+            }
+        }
+        Err(e) => {
+            // A failed run may not have got to everything it reads
+            script.read.extend(done.read);
+            sensors.set_error(&done.id, format!("{:?}", e));
+            passed_nothing(scripts, done.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::REMOTE_START;
+
+    fn script(reads: &[SensorId]) -> Script {
+        Script {
+            compiled: Err("Not compiled".into()),
+            scope: Some(Scope::new()),
+            read: HashSet::new(),
+            reads: reads.iter().copied().collect(),
+            rate: Duration::from_secs(1),
+            trigger: Trigger::Change,
+            last: Instant::now(),
+            started: Some(Instant::now()),
+            pending: false,
+            waiting: false,
+            cycle: None,
+            generation: 0,
+        }
+    }
+
+    #[test]
+    fn remote_sensors_that_show_up_later_are_read() {
+        let remote = SensorId::from_usize(REMOTE_START);
+        let (calls_remote, local) = (SensorId::Virtual(7), SensorId::Virtual(8));
+
+        // Loaded before the peer sent its sensors, remote() found nothing
+        let mut scripts = HashMap::new();
+        scripts.insert(calls_remote, script(&[]));
+        scripts.insert(local, script(&[SensorId::Tmp0]));
+
+        let now_reads = |id: &SensorId| -> HashSet<SensorId> {
+            match *id == calls_remote {
+                true => vec![remote].into_iter().collect(),
+                false => vec![SensorId::Tmp0].into_iter().collect(),
+            }
+        };
+        remote_changed(&mut scripts, now_reads);
+
+        assert!(scripts[&calls_remote].reads(&remote));
+        assert!(scripts[&calls_remote].pending);
+        assert!(scripts[&calls_remote].next_run(Instant::now()).is_some());
+        assert!(!scripts[&local].pending);
+
+        // Nothing new the next time the peer sends it
+        scripts.get_mut(&calls_remote).unwrap().pending = false;
+        remote_changed(&mut scripts, now_reads);
+        assert!(!scripts[&calls_remote].pending);
+    }
+}
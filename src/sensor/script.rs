@@ -0,0 +1,809 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+#[allow(deprecated)]
+use rhai::{ASTNode, Expr, Stmt};
+use rhai::{
+    Array, Dynamic, Engine, EvalAltResult, ImmutableString, ParseError, RegisterFn,
+    RegisterResultFn, Scope, AST, FLOAT, INT,
+};
+use serde::{Deserialize, Serialize};
+
+use super::library::{Library, Resolver};
+use super::{SensorId, Sensors};
+use crate::{Config, Global, ScriptLimits};
+
+/// The script an engine is evaluating, set before each evaluation
+#[derive(Debug)]
+pub struct Run {
+    pub id: SensorId,
+    /// When the sensor was last evaluated
+    pub last: Instant,
+    /// The sensors the script read so far
+    pub read: HashSet<SensorId>,
+    /// When the evaluation started, the script timeout counts from here
+    pub started: Instant,
+}
+
+impl Run {
+    pub fn new(id: SensorId, last: Instant) -> Run {
+        Run {
+            id,
+            last,
+            read: HashSet::new(),
+            started: Instant::now(),
+        }
+    }
+}
+
+pub type Current = Arc<Mutex<Run>>;
+
+type ScriptResult = Result<Dynamic, Box<EvalAltResult>>;
+
+/// An engine with every function a virtual sensor script can call, they act on the
+/// sensor in `current`
+pub fn engine(current: Current, limits: &ScriptLimits) -> Engine {
+    let mut eng = Engine::new();
+
+    // Scripts import from the module library, never files on the server
+    eng.set_module_resolver(Resolver);
+
+    sandbox(&mut eng, current.clone(), limits);
+    register_math(&mut eng);
+
+    let c = current.clone();
+    eng.register_result_fn("sensor", move |index: INT| -> ScriptResult {
+        Ok(latest(&c, index, 1)?[0].into())
+    });
+
+    let c = current.clone();
+    // Federated sensors get their ids here, so scripts find them by instance and alias
+    eng.register_result_fn(
+        "remote",
+        move |instance: ImmutableString, alias: ImmutableString| -> ScriptResult {
+            let sensors = Sensors::global();
+
+            let found = sensors.iter().find(|s| {
+                s.instance.as_deref() == Some(instance.as_str()) && s.alias == alias.as_str()
+            });
+
+            let (id, value) = match found {
+                Some(s) => (*s.key(), s.values.front().copied()),
+                None => return Err(format!("Could not find {} on {}", alias, instance).into()),
+            };
+
+            lock(&c).read.insert(id);
+
+            match value {
+                Some(val) => Ok(val.into()),
+                None => Err(format!("{} on {} has no value yet", alias, instance).into()),
+            }
+        },
+    );
+
+    let c = current.clone();
+    eng.register_result_fn("history", move |index: INT, n: INT| -> ScriptResult {
+        let values: Array = latest(&c, index, n)?
+            .into_iter()
+            .map(Dynamic::from)
+            .collect();
+        Ok(values.into())
+    });
+
+    let c = current.clone();
+    eng.register_result_fn("avg", move |index: INT, n: INT| -> ScriptResult {
+        let values = latest(&c, index, n)?;
+        Ok((values.iter().sum::<f64>() / values.len() as f64).into())
+    });
+
+    let c = current.clone();
+    eng.register_result_fn("min", move |index: INT, n: INT| -> ScriptResult {
+        let values = latest(&c, index, n)?;
+        Ok(values.into_iter().fold(f64::INFINITY, f64::min).into())
+    });
+
+    let c = current.clone();
+    eng.register_result_fn("max", move |index: INT, n: INT| -> ScriptResult {
+        let values = latest(&c, index, n)?;
+        Ok(values.into_iter().fold(f64::NEG_INFINITY, f64::max).into())
+    });
+
+    let c = current.clone();
+    eng.register_result_fn("delta", move |index: INT| -> ScriptResult {
+        match latest(&c, index, 2)?.as_slice() {
+            [newest, previous] => Ok((newest - previous).into()),
+            _ => Err(format!("{:?} needs two values for a delta", sensor_id(index)).into()),
+        }
+    });
+
+    let c = current.clone();
+    eng.register_result_fn("ema", move |index: INT, alpha: FLOAT| -> ScriptResult {
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            return Err(format!("ema alpha must be above 0 and at most 1, not {}", alpha).into());
+        }
+
+        let values = latest(&c, index, INT::MAX)?;
+
+        // Oldest first so the newest values weigh the most
+        let mut rev = values.into_iter().rev();
+        let first = rev.next().unwrap_or_default();
+
+        Ok(rev
+            .fold(first, |ema, v| alpha * v + (1.0 - alpha) * ema)
+            .into())
+    });
+
+    let c = current.clone();
+    eng.register_result_fn("elapsed_ms", move || -> ScriptResult {
+        Ok((lock(&c).last.elapsed().as_millis() as INT).into())
+    });
+
+    // 0 until the sensor has a value, so integrators can start from nothing
+    eng.register_result_fn("self_prev", move || -> ScriptResult {
+        let id = lock(&current).id;
+        Ok(Sensors::global().get_value(&id).unwrap_or(0.0).into())
+    });
+
+    eng
+}
+
+/// The functions that read the sensor given as their first argument
+const READS_SENSOR: &[&str] = &["sensor", "history", "avg", "min", "max", "delta", "ema"];
+
+/// The sensors a source reads with constant arguments, like `sensor(3)`. Sensors picked
+/// at run time are only found when the script runs. A source that does not compile
+/// reads nothing
+pub fn static_dependencies(source: &str) -> BTreeSet<SensorId> {
+    let mut found = BTreeSet::new();
+    find_dependencies(source, None, &mut HashSet::new(), &mut found);
+
+    found
+}
+
+/// Like `static_dependencies`, as if the library module `name` had this source. None
+/// is as if it was removed
+pub fn dependencies_with_module(
+    source: &str,
+    name: &str,
+    module: Option<&str>,
+) -> BTreeSet<SensorId> {
+    let changed = Some((name, module));
+
+    let mut found = BTreeSet::new();
+    find_dependencies(source, changed, &mut HashSet::new(), &mut found);
+
+    found
+}
+
+// Functions from imported modules read sensors too
+fn find_dependencies(
+    source: &str,
+    changed: Option<(&str, Option<&str>)>,
+    seen: &mut HashSet<String>,
+    found: &mut BTreeSet<SensorId>,
+) {
+    let ast = match Engine::new_raw().compile(source) {
+        Ok(ast) => ast,
+        Err(_) => return,
+    };
+
+    ast.walk(&mut |path| {
+        if let Some(ASTNode::Expr(expr)) = path.last() {
+            find_reads(expr, found);
+        }
+    });
+
+    for name in imports(&ast) {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+
+        let module = match changed {
+            Some((changed_name, module)) if changed_name == name => module.map(Into::into),
+            _ => Library::global().source(&name).ok().flatten(),
+        };
+
+        if let Some(module) = module {
+            find_dependencies(&module, changed, seen, found);
+        }
+    }
+}
+
+/// The library modules a source imports by name
+pub fn static_imports(source: &str) -> BTreeSet<String> {
+    match Engine::new_raw().compile(source) {
+        Ok(ast) => imports(&ast),
+        Err(_) => BTreeSet::new(),
+    }
+}
+
+#[allow(deprecated)]
+fn imports(ast: &AST) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+
+    ast.walk(&mut |path| {
+        if let Some(ASTNode::Stmt(Stmt::Import(Expr::StringConstant(name, _), _, _))) = path.last()
+        {
+            found.insert(name.to_string());
+        }
+    });
+
+    found
+}
+
+/// Rhai only walks statements, so the arguments of calls are searched here
+#[allow(deprecated)]
+fn find_reads(expr: &Expr, found: &mut BTreeSet<SensorId>) {
+    match expr {
+        Expr::FnCall(call, _) => {
+            match (call.name.as_ref(), call.args.as_slice()) {
+                (name, [Expr::IntegerConstant(index, _), ..]) if READS_SENSOR.contains(&name) => {
+                    found.insert(sensor_id(*index));
+                }
+                ("remote", [Expr::StringConstant(instance, _), Expr::StringConstant(alias, _)]) => {
+                    let sensors = Sensors::global();
+                    let remote = sensors.iter().find(|s| {
+                        s.instance.as_deref() == Some(instance.as_str())
+                            && s.alias == alias.as_str()
+                    });
+
+                    if let Some(s) = remote {
+                        found.insert(*s.key());
+                    }
+                }
+                _ => {}
+            }
+
+            call.args.iter().for_each(|arg| find_reads(arg, found));
+        }
+        Expr::Dot(x, _) | Expr::Index(x, _) | Expr::In(x, _) | Expr::And(x, _) | Expr::Or(x, _) => {
+            find_reads(&x.lhs, found);
+            find_reads(&x.rhs, found);
+        }
+        Expr::Array(x, _) => x.iter().for_each(|e| find_reads(e, found)),
+        Expr::Map(x, _) => x.iter().for_each(|(_, e)| find_reads(e, found)),
+        Expr::Stmt(x, _) => x.iter().for_each(|stmt| {
+            stmt.walk(&mut vec![], &mut |path| {
+                if let Some(ASTNode::Expr(expr)) = path.last() {
+                    find_reads(expr, found);
+                }
+            })
+        }),
+        _ => {}
+    }
+}
+
+/// Keep user scripts from hanging or starving the worker
+fn sandbox(eng: &mut Engine, current: Current, limits: &ScriptLimits) {
+    eng.set_max_operations(limits.max_operations)
+        .set_max_call_levels(limits.max_call_levels)
+        .set_max_string_size(limits.max_string_size)
+        .set_max_array_size(limits.max_array_size)
+        .set_max_map_size(limits.max_array_size);
+
+    let timeout = limits.timeout;
+
+    // Not from the operation count, that starts over in every imported module
+    eng.on_progress(move |_| match lock(&current).started.elapsed() > timeout {
+        true => Some(format!("Script ran for longer than {} ms", timeout.as_millis()).into()),
+        false => None,
+    });
+}
+
+// Rhai only does arithmetic and comparisons between values of the same type
+macro_rules! mixed_ops {
+    ($eng:ident, $($op:tt)*) => {
+        $(
+            $eng.register_fn(stringify!($op), |x: INT, y: FLOAT| (x as FLOAT) $op y);
+            $eng.register_fn(stringify!($op), |x: FLOAT, y: INT| x $op (y as FLOAT));
+        )*
+    };
+}
+
+// Registers `$f` for every mix of int and float arguments
+macro_rules! float_fn {
+    ($eng:ident, $name:expr, $f:expr, 2) => {
+        $eng.register_fn($name, |x: FLOAT, y: FLOAT| $f(x, y));
+        $eng.register_fn($name, |x: FLOAT, y: INT| $f(x, y as FLOAT));
+        $eng.register_fn($name, |x: INT, y: FLOAT| $f(x as FLOAT, y));
+        $eng.register_fn($name, |x: INT, y: INT| $f(x as FLOAT, y as FLOAT));
+    };
+    ($eng:ident, $name:expr, $f:expr, 3) => {
+        $eng.register_fn($name, |x: FLOAT, y: FLOAT, z: FLOAT| $f(x, y, z));
+        $eng.register_fn($name, |x: FLOAT, y: FLOAT, z: INT| $f(x, y, z as FLOAT));
+        $eng.register_fn($name, |x: FLOAT, y: INT, z: FLOAT| $f(x, y as FLOAT, z));
+        $eng.register_fn($name, |x: FLOAT, y: INT, z: INT| {
+            $f(x, y as FLOAT, z as FLOAT)
+        });
+        $eng.register_fn($name, |x: INT, y: FLOAT, z: FLOAT| $f(x as FLOAT, y, z));
+        $eng.register_fn($name, |x: INT, y: FLOAT, z: INT| {
+            $f(x as FLOAT, y, z as FLOAT)
+        });
+        $eng.register_fn($name, |x: INT, y: INT, z: FLOAT| {
+            $f(x as FLOAT, y as FLOAT, z)
+        });
+    };
+}
+
+/// Math on sensor values, which are floats while literals in scripts are often ints
+fn register_math(eng: &mut Engine) {
+    mixed_ops!(eng, + - * / %);
+    mixed_ops!(eng, < <= > >= == !=);
+
+    float_fn!(eng, "pow", FLOAT::powf, 2);
+
+    // Not FLOAT::clamp, that panics when the bounds are the wrong way around
+    float_fn!(eng, "clamp", clamp, 3);
+    eng.register_fn("clamp", |x: INT, lo: INT, hi: INT| x.max(lo).min(hi));
+
+    // The builtin round always rounds up
+    eng.register_fn("round", FLOAT::round);
+
+    eng.register_fn("exp", |x: INT| (x as FLOAT).exp());
+    eng.register_fn("ln", |x: INT| (x as FLOAT).ln());
+}
+
+fn clamp(x: FLOAT, lo: FLOAT, hi: FLOAT) -> FLOAT {
+    x.max(lo).min(hi)
+}
+
+/// Run a script and turn what it returned into a sensor value. Variables the script
+/// declares at the top level stay in the scope for the next run
+pub fn evaluate(
+    eng: &Engine,
+    ast: &AST,
+    scope: &mut Scope<'static>,
+) -> Result<f64, Box<EvalAltResult>> {
+    let result = eng.eval_ast_with_scope::<Dynamic>(scope, ast);
+
+    // Every run declares its variables again, only the newest of each is kept
+    compact(scope);
+
+    to_value(result?)
+}
+
+/// A compile or runtime error, line and column are 0 when there is no position
+#[derive(Debug)]
+pub struct ScriptError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<ParseError> for ScriptError {
+    fn from(e: ParseError) -> ScriptError {
+        ScriptError {
+            message: e.0.to_string(),
+            line: e.1.line().unwrap_or(0),
+            column: e.1.position().unwrap_or(0),
+        }
+    }
+}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(mut e: Box<EvalAltResult>) -> ScriptError {
+        let position = e.position();
+
+        ScriptError {
+            message: e.clear_position().to_string(),
+            line: position.line().unwrap_or(0),
+            column: position.position().unwrap_or(0),
+        }
+    }
+}
+
+/// What a trial run of a source gave
+#[derive(Debug)]
+pub struct Validation {
+    pub result: Result<f64, ScriptError>,
+    /// The sensors the source read, also when it failed
+    pub dependencies: Vec<SensorId>,
+    pub eval_time: Duration,
+}
+
+/// Compile and run a source once against the current sensor values, as the sensor
+/// `id` would. Nothing is saved and the sensor keeps its own script and state
+pub fn validate(id: SensorId, source: &str) -> Validation {
+    let current = Arc::new(Mutex::new(Run::new(id, Instant::now())));
+    let eng = engine(current.clone(), &Config::global().script_limits);
+
+    let mut eval_time = Duration::default();
+
+    let result = eng
+        .compile(source)
+        .map_err(ScriptError::from)
+        .and_then(|ast| {
+            let started = Instant::now();
+            lock(&current).started = started;
+            let result = evaluate(&eng, &ast, &mut Scope::new());
+            eval_time = started.elapsed();
+
+            result.map_err(ScriptError::from)
+        });
+
+    let mut dependencies: Vec<SensorId> = lock(&current).read.iter().copied().collect();
+    dependencies.sort();
+
+    Validation {
+        result,
+        dependencies,
+        eval_time,
+    }
+}
+
+fn compact(scope: &mut Scope<'static>) {
+    let mut seen = HashSet::new();
+
+    let all: Vec<(&str, bool, Dynamic)> = scope.iter().collect();
+
+    let kept: Vec<(String, bool, Dynamic)> = all
+        .into_iter()
+        .rev()
+        .filter(|(name, _, _)| seen.insert(name.to_string()))
+        .map(|(name, constant, value)| (name.to_string(), constant, value))
+        .collect();
+
+    scope.clear();
+
+    for (name, constant, value) in kept.into_iter().rev() {
+        match constant {
+            true => scope.push_constant_dynamic(name, value),
+            false => scope.push_dynamic(name, value),
+        };
+    }
+}
+
+/// A script variable as it is saved to disk
+#[derive(Serialize, Deserialize, Debug)]
+enum Saved {
+    Int(INT),
+    Float(FLOAT),
+    Bool(bool),
+    Str(String),
+    Array(Vec<Saved>),
+}
+
+impl Saved {
+    /// Maps, closures and the like are not saved
+    fn from_dynamic(value: Dynamic) -> Option<Saved> {
+        Some(if value.is::<INT>() {
+            Saved::Int(value.cast())
+        } else if value.is::<FLOAT>() {
+            Saved::Float(value.cast())
+        } else if value.is::<bool>() {
+            Saved::Bool(value.cast())
+        } else if value.is::<ImmutableString>() {
+            Saved::Str(value.cast::<ImmutableString>().into())
+        } else if value.is::<Array>() {
+            let values = value.cast::<Array>().into_iter();
+            Saved::Array(values.map(Saved::from_dynamic).collect::<Option<_>>()?)
+        } else {
+            return None;
+        })
+    }
+
+    fn into_dynamic(self) -> Dynamic {
+        match self {
+            Saved::Int(v) => v.into(),
+            Saved::Float(v) => v.into(),
+            Saved::Bool(v) => v.into(),
+            Saved::Str(v) => v.into(),
+            Saved::Array(v) => v
+                .into_iter()
+                .map(Saved::into_dynamic)
+                .collect::<Array>()
+                .into(),
+        }
+    }
+}
+
+fn state_tree() -> Result<sled::Tree> {
+    Ok(sled::Db::global().open_tree("script-state")?)
+}
+
+/// The scope a script starts with, saved variables are restored if state is persisted
+pub fn restore_state(id: SensorId) -> Scope<'static> {
+    let mut scope = Scope::new();
+
+    if !Config::global().persist_script_state {
+        return scope;
+    }
+
+    let saved: Vec<(String, Saved)> = match state_tree().and_then(|tree| {
+        Ok(match tree.get(id.to_be_bytes())? {
+            Some(data) => bincode::deserialize(&data)?,
+            None => vec![],
+        })
+    }) {
+        Ok(saved) => saved,
+        Err(e) => {
+            log::error!("Could not restore script state of {:?} {}", id, e);
+            vec![]
+        }
+    };
+
+    for (name, value) in saved {
+        scope.push_dynamic(name, value.into_dynamic());
+    }
+
+    scope
+}
+
+/// Save the variables of a script if state is persisted and they changed
+pub fn save_state(id: SensorId, scope: &Scope) -> Result<()> {
+    if !Config::global().persist_script_state {
+        return Ok(());
+    }
+
+    let saved: Vec<(String, Saved)> = scope
+        .iter()
+        .filter(|(_, constant, _)| !constant)
+        .filter_map(|(name, _, value)| Some((name.to_string(), Saved::from_dynamic(value)?)))
+        .collect();
+
+    let data = bincode::serialize(&saved)?;
+    let tree = state_tree()?;
+
+    // Most runs leave the variables as they were, that is not worth a write
+    if tree.get(id.to_be_bytes())?.as_deref() != Some(data.as_slice()) {
+        tree.insert(id.to_be_bytes(), data)?;
+    }
+
+    Ok(())
+}
+
+/// Forget the saved variables of a script
+pub fn clear_state(id: SensorId) -> Result<()> {
+    state_tree()?.remove(id.to_be_bytes())?;
+
+    Ok(())
+}
+
+/// Scripts may return ints or floats, anything else is an error
+fn to_value(result: Dynamic) -> Result<f64, Box<EvalAltResult>> {
+    let value = match (result.as_float(), result.as_int()) {
+        (Ok(value), _) => value,
+        (_, Ok(value)) => value as FLOAT,
+        _ => {
+            let message = format!("Script must return a number, not {}", result.type_name());
+            return Err(message.into());
+        }
+    };
+
+    match value.is_finite() {
+        true => Ok(value),
+        false => Err(format!("Script returned {}", value).into()),
+    }
+}
+
+fn lock(current: &Current) -> std::sync::MutexGuard<'_, Run> {
+    current.lock().expect("Current script poisoned")
+}
+
+fn sensor_id(index: INT) -> SensorId {
+    SensorId::from_usize(index as usize)
+}
+
+/// Up to `n` values of a sensor, newest first. There is always at least one
+fn latest(current: &Current, index: INT, n: INT) -> Result<Vec<f64>, Box<EvalAltResult>> {
+    let id = sensor_id(index);
+
+    let values: Vec<f64> = match Sensors::global().get(&id) {
+        Some(s) => s.values.iter().take(n.max(1) as usize).copied().collect(),
+        None => return Err(format!("Could not find {:?}", id).into()),
+    };
+
+    // Depend on it even without values, so the script runs again when one arrives
+    lock(current).read.insert(id);
+
+    if values.is_empty() {
+        return Err(format!("{:?} has no values yet", id).into());
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str) -> Result<f64, String> {
+        eval_with(src, &mut Scope::new())
+    }
+
+    fn limits() -> ScriptLimits {
+        ScriptLimits {
+            max_operations: 1_000_000,
+            max_call_levels: 32,
+            max_string_size: 1000,
+            max_array_size: 1000,
+            timeout: Duration::from_millis(200),
+        }
+    }
+
+    fn eval_with(src: &str, scope: &mut Scope<'static>) -> Result<f64, String> {
+        eval_limited(src, scope, &limits())
+    }
+
+    fn eval_limited(
+        src: &str,
+        scope: &mut Scope<'static>,
+        limits: &ScriptLimits,
+    ) -> Result<f64, String> {
+        let current = Arc::new(Mutex::new(Run::new(SensorId::Virtual(7), Instant::now())));
+        let eng = engine(current, limits);
+        let ast = eng.compile(src).map_err(|e| format!("{:?}", e))?;
+
+        evaluate(&eng, &ast, scope).map_err(|e| format!("{:?}", e))
+    }
+
+    #[test]
+    fn int_and_float_returns() {
+        assert_eq!(eval("21 * 2"), Ok(42.0));
+        assert_eq!(eval("(20.5 + 21.5) / 2.0"), Ok(21.0));
+        assert_eq!(eval("let x = 7; x"), Ok(7.0));
+        assert_eq!(eval("-3"), Ok(-3.0));
+    }
+
+    #[test]
+    fn mixed_arithmetic() {
+        assert_eq!(eval("1 + 0.5"), Ok(1.5));
+        assert_eq!(eval("0.5 * 4"), Ok(2.0));
+        assert_eq!(eval("3 / 2.0"), Ok(1.5));
+        assert_eq!(eval("3 / 2"), Ok(1.0));
+        assert_eq!(eval("if 1 < 1.5 { 1 } else { 0 }"), Ok(1.0));
+        assert_eq!(eval("if 2.0 == 2 { 1 } else { 0 }"), Ok(1.0));
+    }
+
+    #[test]
+    fn math_functions() {
+        assert_eq!(eval("exp(0.0)"), Ok(1.0));
+        assert_eq!(eval("exp(0)"), Ok(1.0));
+        assert_eq!(eval("ln(1.0)"), Ok(0.0));
+        assert_eq!(eval("pow(2, 10)"), Ok(1024.0));
+        assert_eq!(eval("pow(9.0, 0.5)"), Ok(3.0));
+        assert_eq!(eval("clamp(5, 0, 3)"), Ok(3.0));
+        assert_eq!(eval("clamp(-1.5, 0, 3)"), Ok(0.0));
+        assert_eq!(eval("clamp(1.5, 0.0, 3)"), Ok(1.5));
+        assert_eq!(eval("round(2.4)"), Ok(2.0));
+    }
+
+    #[test]
+    fn non_numbers_are_errors() {
+        assert!(eval(r#""hot""#).is_err());
+        assert!(eval("true").is_err());
+        assert!(eval("()").is_err());
+        assert!(eval("1.0 / 0.0").is_err());
+    }
+
+    #[test]
+    fn state_is_kept_between_runs() {
+        let counter = "let n = if is_def_var(\"n\") { n + 1 } else { 1 }; n";
+        let mut scope = Scope::new();
+
+        assert_eq!(eval_with(counter, &mut scope), Ok(1.0));
+        assert_eq!(eval_with(counter, &mut scope), Ok(2.0));
+        assert_eq!(eval_with(counter, &mut scope), Ok(3.0));
+        assert_eq!(scope.len(), 1);
+
+        // A fresh scope is a reset
+        assert_eq!(eval_with(counter, &mut Scope::new()), Ok(1.0));
+    }
+
+    #[test]
+    fn saved_values_round_trip() {
+        let mut scope = Scope::new();
+        eval_with(
+            "let a = 1; let b = 2.5; let c = [true, \"x\"]; 0",
+            &mut scope,
+        )
+        .unwrap();
+
+        let saved: Vec<Saved> = scope
+            .iter()
+            .filter_map(|(_, _, value)| Saved::from_dynamic(value))
+            .collect();
+        let data = bincode::serialize(&saved).unwrap();
+        let restored: Vec<Saved> = bincode::deserialize(&data).unwrap();
+
+        let mut restored: Vec<Dynamic> = restored.into_iter().map(Saved::into_dynamic).collect();
+        let c = restored.pop().unwrap().cast::<Array>();
+
+        assert_eq!(restored[0].as_int(), Ok(1));
+        assert_eq!(restored[1].as_float(), Ok(2.5));
+        assert_eq!(c[0].as_bool(), Ok(true));
+        assert_eq!(c[1].clone().cast::<ImmutableString>().as_str(), "x");
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        let err = eval("loop {}").unwrap_err();
+        assert!(err.contains("TooManyOperations"), "{}", err);
+
+        let err = eval("fn f(x) { f(x + 1) } f(0)").unwrap_err();
+        assert!(err.contains("StackOverflow"), "{}", err);
+
+        let err = eval("let s = \"\"; loop { s = s + \"xxxxxxxxxx\"; }").unwrap_err();
+        assert!(err.contains("DataTooLarge"), "{}", err);
+
+        let err = eval("let a = []; loop { a = a + [1, 2, 3]; }").unwrap_err();
+        assert!(err.contains("DataTooLarge"), "{}", err);
+    }
+
+    #[test]
+    fn slow_scripts_time_out() {
+        let limits = ScriptLimits {
+            max_operations: u64::MAX,
+            ..limits()
+        };
+        let started = Instant::now();
+
+        let err = eval_limited("loop {}", &mut Scope::new(), &limits).unwrap_err();
+        assert!(err.contains("longer than 200 ms"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // The clock starts over for the next evaluation
+        assert_eq!(eval_limited("1", &mut Scope::new(), &limits), Ok(1.0));
+    }
+
+    #[test]
+    fn timeouts_count_from_the_start_of_the_run() {
+        let current = Arc::new(Mutex::new(Run::new(SensorId::Virtual(7), Instant::now())));
+        let eng = engine(current.clone(), &limits());
+        let ast = eng.compile("let x = 1; x + 1").unwrap();
+
+        // Like a run that spent its time in imported modules, each counting from 1
+        current.lock().unwrap().started = Instant::now() - Duration::from_secs(1);
+
+        let err = format!("{:?}", evaluate(&eng, &ast, &mut Scope::new()).unwrap_err());
+        assert!(err.contains("longer than 200 ms"), "{}", err);
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let current = Arc::new(Mutex::new(Run::new(SensorId::Virtual(7), Instant::now())));
+        let eng = engine(current, &limits());
+
+        let e = ScriptError::from(eng.compile("let x = 1;\nlet = 2;").unwrap_err());
+        assert_eq!((e.line, e.column), (2, 5));
+
+        let ast = eng.compile("let x = 1;\n  x + y").unwrap();
+        let e = ScriptError::from(evaluate(&eng, &ast, &mut Scope::new()).unwrap_err());
+        assert_eq!((e.line, e.column), (2, 7));
+        assert!(e.message.contains("y"), "{}", e.message);
+    }
+
+    #[test]
+    fn constant_dependencies_are_found() {
+        let deps = static_dependencies("let a = sensor(1); avg(2, 10) + a + max(3, 5)");
+        let expected: BTreeSet<SensorId> = vec![SensorId::Tmp1, SensorId::Tmp2, SensorId::Tmp3]
+            .into_iter()
+            .collect();
+        assert_eq!(deps, expected);
+
+        let deps = static_dependencies("fn f() { ema(7, 0.5) } let i = 4; sensor(i) + f()");
+        assert_eq!(
+            deps.into_iter().collect::<Vec<_>>(),
+            vec![SensorId::Virtual(7)]
+        );
+
+        assert!(static_dependencies("sensor(").is_empty());
+    }
+
+    #[test]
+    fn imports_are_found() {
+        let imports = static_imports(
+            r#"import "filters" as f; import "ntc" as ntc; let m = "x"; ntc::celsius(sensor(0))"#,
+        );
+        assert_eq!(
+            imports.into_iter().collect::<Vec<_>>(),
+            vec!["filters".to_string(), "ntc".to_string()]
+        );
+
+        assert!(static_imports("import ").is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use nino::pem::{load_certs, load_key};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig,
+        Session,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::{
+    auth::{self, Access},
+    TlsConfig,
+};
+
+pub fn acceptor(cfg: &TlsConfig) -> Result<TlsAcceptor> {
+    let verifier = match cfg.client_ca {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+
+            for cert in load_certs(path)? {
+                roots.add(&cert)?;
+            }
+
+            // Clients without a certificate can still authenticate in the ready
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    config.set_single_cert(load_certs(&cfg.cert)?, load_key(&cfg.key)?)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The access level granted by the certificate a client presented, if any
+pub fn peer_access(stream: &TlsStream<TcpStream>) -> Result<Option<Access>> {
+    let (_, session) = stream.get_ref();
+
+    match session.get_peer_certificates() {
+        Some(certs) => match certs.first() {
+            Some(cert) => Ok(Some(auth::certificate_access(&cert.0)?)),
+            None => Ok(None),
+        },
+        None => Ok(None),
+    }
+}